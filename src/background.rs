@@ -0,0 +1,323 @@
+use crate::light::RenderLightKind;
+use crate::render::{DeserializeError, RenderColor, RenderEnv};
+use crate::vec3::Vec3;
+use image::codecs::hdr::HdrDecoder;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Signature of a user defined background, which returns the color seen along `direction`.
+pub type BackgroundFn = dyn Fn(&RenderEnv, &Vec3) -> RenderColor + Send + Sync;
+
+/// What rays that escape the scene see.
+#[derive(Clone)]
+pub enum Background {
+    Solid(RenderColor),
+    /// Linear blend from `bottom`, looking straight down, to `top`, looking straight up.
+    Gradient {
+        bottom: RenderColor,
+        top: RenderColor,
+    },
+    /// Procedural grid pattern with a sun in the direction of each directional light.
+    Sky,
+    /// Equirectangular (latitude-longitude) panorama, either an ordinary image, whose values
+    /// are decoded from sRGB, or a high dynamic range `.hdr` or `.exr` file, whose values are
+    /// linear already. `rotation` turns it around the vertical axis in radians and `intensity`
+    /// scales its brightness.
+    Image {
+        path: String,
+        image: Arc<image::Rgb32FImage>,
+        rotation: f32,
+        intensity: f32,
+    },
+    /// Arbitrary function set through the library API. It can't be saved to a scene file.
+    #[allow(dead_code)]
+    Custom(Arc<BackgroundFn>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum BackgroundSerial {
+    Solid(RenderColor),
+    Gradient {
+        bottom: RenderColor,
+        top: RenderColor,
+    },
+    Sky,
+    Image {
+        path: String,
+        #[serde(default)]
+        rotation: f32,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+}
+
+fn default_intensity() -> f32 {
+    1.
+}
+
+impl Background {
+    #[allow(dead_code)]
+    pub fn custom(f: impl Fn(&RenderEnv, &Vec3) -> RenderColor + Send + Sync + 'static) -> Self {
+        Background::Custom(Arc::new(f))
+    }
+
+    /// Error when open image failed
+    #[allow(dead_code)]
+    pub fn image(path: &str, rotation: f32, intensity: f32) -> Result<Self, image::ImageError> {
+        Self::load_image(path, Path::new(path), rotation, intensity)
+    }
+
+    /// Loads the image at `file`, which `path` as written in the scene refers to.
+    fn load_image(
+        path: &str,
+        file: &Path,
+        rotation: f32,
+        intensity: f32,
+    ) -> Result<Self, image::ImageError> {
+        Ok(Background::Image {
+            path: path.to_string(),
+            image: Arc::new(load_linear(file)?),
+            rotation,
+            intensity,
+        })
+    }
+
+    pub fn sample(&self, ren: &RenderEnv, direction: &Vec3) -> RenderColor {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { bottom, top } => {
+                let f = (direction.y + 1.) / 2.;
+                *bottom * (1. - f) + *top * f
+            }
+            Background::Sky => sky(ren, direction),
+            Background::Image {
+                image,
+                rotation,
+                intensity,
+                ..
+            } => {
+                let (u, v) = equirectangular(direction);
+                bilinear(image, u - rotation / (2. * PI), v) * *intensity
+            }
+            Background::Custom(f) => f(ren, direction),
+        }
+    }
+
+    /// Returns None for a custom background.
+    pub fn serialize(&self) -> Option<BackgroundSerial> {
+        Some(match self {
+            Background::Solid(color) => BackgroundSerial::Solid(*color),
+            Background::Gradient { bottom, top } => BackgroundSerial::Gradient {
+                bottom: *bottom,
+                top: *top,
+            },
+            Background::Sky => BackgroundSerial::Sky,
+            Background::Image {
+                path,
+                rotation,
+                intensity,
+                ..
+            } => BackgroundSerial::Image {
+                path: path.clone(),
+                rotation: *rotation,
+                intensity: *intensity,
+            },
+            Background::Custom(_) => return None,
+        })
+    }
+
+    /// Relative image paths are resolved against `scene_dir`.
+    pub fn deserialize(
+        serial: &BackgroundSerial,
+        scene_dir: &Path,
+    ) -> Result<Self, DeserializeError> {
+        Ok(match serial {
+            BackgroundSerial::Solid(color) => Background::Solid(*color),
+            BackgroundSerial::Gradient { bottom, top } => Background::Gradient {
+                bottom: *bottom,
+                top: *top,
+            },
+            BackgroundSerial::Sky => Background::Sky,
+            BackgroundSerial::Image {
+                path,
+                rotation,
+                intensity,
+            } => Self::load_image(path, &scene_dir.join(path), *rotation, *intensity).map_err(
+                |e| DeserializeError::new(&format!("Background couldn't load {}: {}", path, e)),
+            )?,
+        })
+    }
+}
+
+/// Loads an image as linear intensities. Integer formats like PNG and JPEG store sRGB encoded
+/// values, while floating point ones store linear values.
+fn load_linear(path: &Path) -> Result<image::Rgb32FImage, image::ImageError> {
+    let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    if reader.format() == Some(image::ImageFormat::Hdr) {
+        // The generic decoder clamps Radiance files to 8 bit sRGB, so read the floats directly
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+        let width = decoder.metadata().width;
+        let pixels = decoder.read_image_hdr()?;
+        let height = pixels.len() as u32 / width.max(1);
+        return Ok(image::Rgb32FImage::from_fn(width, height, |x, y| {
+            pixels[(y * width + x) as usize]
+        }));
+    }
+    let image = reader.decode()?;
+    let linear = matches!(
+        image,
+        image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_)
+    );
+    let mut image = image.into_rgb32f();
+    if !linear {
+        for pixel in image.pixels_mut() {
+            for c in pixel.0.iter_mut() {
+                *c = srgb_decode(*c);
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Converts an sRGB encoded value in [0, 1] to linear intensity.
+fn srgb_decode(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Maps a direction to texture coordinates in [0, 1) of a latitude-longitude image whose top
+/// row is straight up.
+fn equirectangular(direction: &Vec3) -> (f32, f32) {
+    let phi = direction.z.atan2(direction.x);
+    let the = direction.y.clamp(-1., 1.).asin();
+    (phi / (2. * PI) + 0.5, 0.5 - the / PI)
+}
+
+/// Bilinearly filtered lookup that wraps around horizontally and clamps vertically.
+fn bilinear(image: &image::Rgb32FImage, u: f32, v: f32) -> RenderColor {
+    let (w, h) = (image.width() as i64, image.height() as i64);
+    let x = u.rem_euclid(1.) * w as f32 - 0.5;
+    let y = v * h as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |x: i64, y: i64| {
+        let pixel = image.get_pixel(x.rem_euclid(w) as u32, y.clamp(0, h - 1) as u32);
+        RenderColor::new(pixel[0], pixel[1], pixel[2])
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    (texel(x0, y0) * (1. - fx) + texel(x0 + 1, y0) * fx) * (1. - fy)
+        + (texel(x0, y0 + 1) * (1. - fx) + texel(x0 + 1, y0 + 1) * fx) * fy
+}
+
+fn sky(ren: &RenderEnv, direction: &Vec3) -> RenderColor {
+    let phi = direction.z.atan2(direction.x);
+    let the = direction.y.asin();
+    let d = (50. * PI + phi * 10. * PI) % (2. * PI) - PI;
+    let dd = (50. * PI + the * 10. * PI) % (2. * PI) - PI;
+    let ret = RenderColor::new(
+        0.5 / (15. * (d * d * dd * dd) + 1.),
+        0.25 - direction.y / 4.,
+        0.25 - direction.y / 4.,
+    );
+    let dot = ren
+        .lights
+        .iter()
+        .filter_map(|light| match light.kind {
+            RenderLightKind::Directional { direction: sun } => Some(sun.dot(direction)),
+            _ => None,
+        })
+        .fold(-1., f32::max);
+
+    if dot > 0.9 {
+        if 0.9995 < dot {
+            RenderColor::new(2., 2., 2.)
+        } else {
+            let ret2 = if 0.995 < dot {
+                let dd = (dot - 0.995) * 150.;
+                RenderColor::new(ret.r + dd, ret.g + dd, ret.b + dd)
+            } else {
+                ret
+            };
+            let dot2 = dot - 0.9;
+            RenderColor::new(ret2.r + dot2 * 5., ret2.g + dot2 * 5., ret2.b)
+        }
+    } else {
+        ret
+    }
+    // else PointMandel(dir->x * 2., dir->z * 2., 32, ret);
+}
+
+#[test]
+fn test_equirectangular() {
+    let (u, v) = equirectangular(&Vec3::new(1., 0., 0.));
+    assert_eq!((u, v), (0.5, 0.5));
+    assert_eq!(equirectangular(&Vec3::new(0., 1., 0.)).1, 0.);
+    assert_eq!(equirectangular(&Vec3::new(0., -1., 0.)).1, 1.);
+    assert_eq!(equirectangular(&Vec3::new(0., 0., 1.)).0, 0.75);
+}
+
+#[test]
+fn test_bilinear() {
+    let image = image::Rgb32FImage::from_fn(4, 2, |x, y| image::Rgb([x as f32, y as f32, 1.]));
+    // Texel centers sample exactly
+    assert_eq!(bilinear(&image, 0.125, 0.25).r, 0.);
+    assert_eq!(bilinear(&image, 0.375, 0.75).g, 1.);
+    // Halfway between the last and the first column wraps around
+    assert_eq!(bilinear(&image, 1., 0.25).r, 1.5);
+    assert_eq!(bilinear(&image, 0.25, 0.5).r, 0.5);
+    assert_eq!(bilinear(&image, 0.25, 0.).g, 0.);
+}
+
+#[test]
+fn test_load_linear() {
+    let dir = std::env::temp_dir().join("ray-rust-test-load-linear");
+    std::fs::create_dir_all(&dir).unwrap();
+    let ldr = dir.join("gray.png");
+    image::RgbImage::from_pixel(1, 1, image::Rgb([188, 188, 188]))
+        .save(&ldr)
+        .unwrap();
+    let exr = dir.join("gray.exr");
+    image::Rgb32FImage::from_pixel(1, 1, image::Rgb([0.5, 0.5, 0.5]))
+        .save(&exr)
+        .unwrap();
+    let hdr = dir.join("bright.hdr");
+    image::codecs::hdr::HdrEncoder::new(File::create(&hdr).unwrap())
+        .encode(&[image::Rgb([4., 0.5, 0.25]); 2], 2, 1)
+        .unwrap();
+    // sRGB 188 is about half the linear intensity of white
+    let ldr = load_linear(&ldr).unwrap().get_pixel(0, 0).0[0];
+    assert!((ldr - 0.5).abs() < 0.01, "{}", ldr);
+    let exr = load_linear(&exr).unwrap().get_pixel(0, 0).0[0];
+    assert_eq!(exr, 0.5);
+    // Radiance files keep values above 1 as they are
+    let hdr = load_linear(&hdr).unwrap();
+    assert_eq!(hdr.dimensions(), (2, 1));
+    assert_eq!(hdr.get_pixel(1, 0).0, [4., 0.5, 0.25]);
+}
+
+#[test]
+fn test_deserialize_image() {
+    let dir = std::env::temp_dir().join("ray-rust-test-deserialize-image");
+    std::fs::create_dir_all(&dir).unwrap();
+    image::RgbImage::from_pixel(2, 1, image::Rgb([255, 255, 255]))
+        .save(dir.join("white.png"))
+        .unwrap();
+    // Paths in scenes are relative to the scene file, and are saved as they were written
+    let serial = BackgroundSerial::Image {
+        path: "white.png".to_string(),
+        rotation: 0.,
+        intensity: 1.,
+    };
+    assert!(Background::deserialize(&serial, Path::new("")).is_err());
+    let background = Background::deserialize(&serial, &dir).ok();
+    assert!(matches!(
+        background.and_then(|b| b.serialize()),
+        Some(BackgroundSerial::Image { path, .. }) if path == "white.png"
+    ));
+}
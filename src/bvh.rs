@@ -0,0 +1,455 @@
+//! Bounding volume hierarchy over axis aligned bounding boxes.
+//!
+//! The hierarchy only stores indices into the caller's primitive list, so the same structure
+//! serves both objects in a scene and triangles in a mesh.
+use crate::vec3::Vec3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    /// An inverted box that is the identity of `union`.
+    pub fn empty() -> Self {
+        let inf = f32::INFINITY;
+        Self::new(Vec3::new(inf, inf, inf), Vec3::new(-inf, -inf, -inf))
+    }
+
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vec3>) -> Self {
+        points
+            .into_iter()
+            .fold(Self::empty(), |acc, p| acc.union(&Self::new(*p, *p)))
+    }
+
+    pub fn union(&self, o: &Self) -> Self {
+        Self::new(
+            Vec3::new(
+                self.min.x.min(o.min.x),
+                self.min.y.min(o.min.y),
+                self.min.z.min(o.min.z),
+            ),
+            Vec3::new(
+                self.max.x.max(o.max.x),
+                self.max.y.max(o.max.y),
+                self.max.z.max(o.max.z),
+            ),
+        )
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0. || d.y < 0. || d.z < 0. {
+            return 0.;
+        }
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test. Returns the distance at which the ray enters the box, if it does so
+    /// before `ray_length`.
+    pub fn intersect(&self, vi: &Vec3, inv_eye: &Vec3, ray_length: f32) -> Option<f32> {
+        let mut tmin = 0f32;
+        let mut tmax = ray_length;
+        for (o, inv, lo, hi) in [
+            (vi.x, inv_eye.x, self.min.x, self.max.x),
+            (vi.y, inv_eye.y, self.min.y, self.max.y),
+            (vi.z, inv_eye.z, self.min.z, self.max.z),
+        ] {
+            let (t0, t1) = ((lo - o) * inv, (hi - o) * inv);
+            let (t0, t1) = if inv < 0. { (t1, t0) } else { (t0, t1) };
+            tmin = tmin.max(t0);
+            tmax = tmax.min(t1);
+            if tmax < tmin {
+                return None;
+            }
+        }
+        Some(tmin)
+    }
+
+    /// Squared distance from `p` to the nearest point in the box, 0 if inside.
+    pub fn distance_squared(&self, p: &Vec3) -> f32 {
+        let dx = (self.min.x - p.x).max(p.x - self.max.x).max(0.);
+        let dy = (self.min.y - p.y).max(p.y - self.max.y).max(0.);
+        let dz = (self.min.z - p.z).max(p.z - self.max.z).max(0.);
+        dx * dx + dy * dy + dz * dz
+    }
+
+    fn axis(v: &Vec3, axis: usize) -> f32 {
+        match axis {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum BvhNode {
+    Leaf {
+        bounds: Aabb,
+        start: usize,
+        count: usize,
+    },
+    Inner {
+        bounds: Aabb,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bounds, .. } | BvhNode::Inner { bounds, .. } => bounds,
+        }
+    }
+}
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+/// Relative cost of visiting a node against testing a primitive in the surface area heuristic.
+const TRAVERSAL_COST: f32 = 0.5;
+/// Below this many primitives, testing them all is faster than testing bounding boxes.
+const LINEAR_SCAN_SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Primitive indices, permuted so that each leaf refers to a contiguous range.
+    indices: Vec<usize>,
+    /// Primitives without finite bounds (e.g. infinite planes) are tested on every query.
+    unbounded: Vec<usize>,
+    len: usize,
+}
+
+impl Bvh {
+    /// Build a hierarchy with the surface area heuristic. `bounds[i]` is `None` for primitives
+    /// that can't be bounded.
+    pub fn build(bounds: &[Option<Aabb>]) -> Self {
+        let mut ret = Self {
+            nodes: vec![],
+            indices: vec![],
+            unbounded: vec![],
+            len: bounds.len(),
+        };
+        let mut items = vec![];
+        for (i, b) in bounds.iter().enumerate() {
+            match b {
+                Some(b) => items.push((i, *b, b.centroid())),
+                None => ret.unbounded.push(i),
+            }
+        }
+        if !items.is_empty() {
+            ret.build_node(&mut items, 0);
+        }
+        ret.indices = items.into_iter().map(|item| item.0).collect();
+        ret
+    }
+
+    /// Number of primitives the hierarchy was built for.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bounds of all bounded primitives.
+    pub fn bounds(&self) -> Aabb {
+        self.nodes
+            .first()
+            .map(|n| *n.bounds())
+            .unwrap_or_else(Aabb::empty)
+    }
+
+    /// Recursively partitions `items`, which is the sub slice of the whole item list starting at
+    /// `offset`, and returns the index of the created node.
+    fn build_node(&mut self, items: &mut [(usize, Aabb, Vec3)], offset: usize) -> usize {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.union(&item.1));
+        let node_idx = self.nodes.len();
+        let leaf = BvhNode::Leaf {
+            bounds,
+            start: offset,
+            count: items.len(),
+        };
+        if items.len() <= 1 {
+            self.nodes.push(leaf);
+            return node_idx;
+        }
+
+        let centroid_bounds = Aabb::from_points(items.iter().map(|item| &item.2));
+        let mut best: Option<(f32, usize, usize)> = None;
+        for axis in 0..3 {
+            let lo = Aabb::axis(&centroid_bounds.min, axis);
+            let extent = Aabb::axis(&centroid_bounds.max, axis) - lo;
+            if extent <= 0. {
+                continue;
+            }
+            let bin_of = |c: &Vec3| {
+                (((Aabb::axis(c, axis) - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+            };
+            let mut bins = [(Aabb::empty(), 0usize); SAH_BINS];
+            for item in items.iter() {
+                let bin = &mut bins[bin_of(&item.2)];
+                bin.0 = bin.0.union(&item.1);
+                bin.1 += 1;
+            }
+            for split in 1..SAH_BINS {
+                let (lb, lc) = bins[..split].iter().fold((Aabb::empty(), 0), |acc, b| {
+                    (acc.0.union(&b.0), acc.1 + b.1)
+                });
+                let (rb, rc) = bins[split..].iter().fold((Aabb::empty(), 0), |acc, b| {
+                    (acc.0.union(&b.0), acc.1 + b.1)
+                });
+                if lc == 0 || rc == 0 {
+                    continue;
+                }
+                let cost = lb.surface_area() * lc as f32 + rb.surface_area() * rc as f32;
+                if best.map(|b| cost < b.0).unwrap_or(true) {
+                    best = Some((cost, axis, split));
+                }
+            }
+        }
+
+        let leaf_cost = items.len() as f32;
+        let split = best.and_then(|(cost, axis, split)| {
+            let cost = TRAVERSAL_COST + cost / bounds.surface_area().max(f32::EPSILON);
+            if cost < leaf_cost || MAX_LEAF_SIZE < items.len() {
+                Some((axis, split))
+            } else {
+                None
+            }
+        });
+
+        let mid = match split {
+            Some((axis, split)) => {
+                let lo = Aabb::axis(&centroid_bounds.min, axis);
+                let extent = Aabb::axis(&centroid_bounds.max, axis) - lo;
+                items.sort_by(|a, b| {
+                    Aabb::axis(&a.2, axis)
+                        .partial_cmp(&Aabb::axis(&b.2, axis))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                items
+                    .iter()
+                    .position(|item| {
+                        SAH_BINS as f32 * (Aabb::axis(&item.2, axis) - lo) / extent >= split as f32
+                    })
+                    .unwrap_or(items.len() / 2)
+            }
+            // All centroids coincide; split in the middle to bound the leaf size.
+            None if MAX_LEAF_SIZE < items.len() => items.len() / 2,
+            None => {
+                self.nodes.push(leaf);
+                return node_idx;
+            }
+        };
+
+        self.nodes.push(leaf);
+        let mid = mid.max(1).min(items.len() - 1);
+        let (left_items, right_items) = items.split_at_mut(mid);
+        let left = self.build_node(left_items, offset);
+        let right = self.build_node(right_items, offset + mid);
+        self.nodes[node_idx] = BvhNode::Inner {
+            bounds,
+            left,
+            right,
+        };
+        node_idx
+    }
+
+    /// Find the closest hit along a ray. `hit(idx, t)` is called for every primitive whose
+    /// bounds the ray passes through before the current closest distance `t`, and must return the
+    /// new closest distance.
+    pub fn raycast(
+        &self,
+        vi: &Vec3,
+        eye: &Vec3,
+        ray_length: f32,
+        mut hit: impl FnMut(usize, f32) -> f32,
+    ) -> f32 {
+        let mut t = ray_length;
+        for &idx in &self.unbounded {
+            t = hit(idx, t);
+        }
+        if self.indices.len() <= LINEAR_SCAN_SIZE {
+            for &idx in &self.indices {
+                t = hit(idx, t);
+            }
+        } else if let Some(root) = self.nodes.first() {
+            let inv_eye = Vec3::new(1. / eye.x, 1. / eye.y, 1. / eye.z);
+            if root.bounds().intersect(vi, &inv_eye, t).is_some() {
+                self.raycast_node(0, vi, &inv_eye, &mut t, &mut hit);
+            }
+        }
+        t
+    }
+
+    // Traversals recurse rather than keep an explicit stack because they run for every ray and
+    // every ray marching step, where allocating or initializing a stack costs more than the
+    // traversal itself in small scenes.
+    fn raycast_node(
+        &self,
+        node_idx: usize,
+        vi: &Vec3,
+        inv_eye: &Vec3,
+        t: &mut f32,
+        hit: &mut impl FnMut(usize, f32) -> f32,
+    ) {
+        match self.nodes[node_idx] {
+            BvhNode::Leaf { start, count, .. } => {
+                for &idx in &self.indices[start..start + count] {
+                    *t = hit(idx, *t);
+                }
+            }
+            BvhNode::Inner { left, right, .. } => {
+                // Visit the nearer child first so that the farther one can be culled
+                let tl = self.nodes[left].bounds().intersect(vi, inv_eye, *t);
+                let tr = self.nodes[right].bounds().intersect(vi, inv_eye, *t);
+                let (first, second) = match (tl, tr) {
+                    (Some(tl), Some(tr)) if tr < tl => (right, Some(left)),
+                    (Some(_), Some(_)) => (left, Some(right)),
+                    (Some(_), None) => (left, None),
+                    (None, Some(_)) => (right, None),
+                    (None, None) => return,
+                };
+                self.raycast_node(first, vi, inv_eye, t, hit);
+                if let Some(second) = second {
+                    if self.nodes[second]
+                        .bounds()
+                        .intersect(vi, inv_eye, *t)
+                        .is_some()
+                    {
+                        self.raycast_node(second, vi, inv_eye, t, hit);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Visit primitives near a point. `visit(idx)` returns the radius beyond which the caller
+    /// is no longer interested, so subtrees farther than that are skipped.
+    pub fn nearest(&self, p: &Vec3, mut visit: impl FnMut(usize) -> f32) {
+        let mut radius = f32::INFINITY;
+        for &idx in &self.unbounded {
+            radius = visit(idx);
+        }
+        if self.indices.len() <= LINEAR_SCAN_SIZE {
+            for &idx in &self.indices {
+                visit(idx);
+            }
+        } else if let Some(root) = self.nodes.first() {
+            // Compare squared distances to save square roots
+            if root.bounds().distance_squared(p) < radius * radius {
+                self.nearest_node(0, p, &mut radius, &mut visit);
+            }
+        }
+    }
+
+    fn nearest_node(
+        &self,
+        node_idx: usize,
+        p: &Vec3,
+        radius: &mut f32,
+        visit: &mut impl FnMut(usize) -> f32,
+    ) {
+        match self.nodes[node_idx] {
+            BvhNode::Leaf { start, count, .. } => {
+                for &idx in &self.indices[start..start + count] {
+                    *radius = visit(idx);
+                }
+            }
+            BvhNode::Inner { left, right, .. } => {
+                let dl = self.nodes[left].bounds().distance_squared(p);
+                let dr = self.nodes[right].bounds().distance_squared(p);
+                let ((first, d1), (second, d2)) = if dl < dr {
+                    ((left, dl), (right, dr))
+                } else {
+                    ((right, dr), (left, dl))
+                };
+                if d1 < *radius * *radius {
+                    self.nearest_node(first, p, radius, visit);
+                }
+                if d2 < *radius * *radius {
+                    self.nearest_node(second, p, radius, visit);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_bvh() {
+    let bounds: Vec<_> = (0..20)
+        .map(|i| {
+            let c = Vec3::new(i as f32 * 3., 0., 0.);
+            Some(Aabb::new(
+                c - Vec3::new(1., 1., 1.),
+                c + Vec3::new(1., 1., 1.),
+            ))
+        })
+        .chain(std::iter::once(None))
+        .collect();
+    let bvh = Bvh::build(&bounds);
+    assert_eq!(bvh.len(), 21);
+
+    // Small hierarchies are scanned linearly, larger ones traversed; both reach every primitive
+    let small = Bvh::build(&bounds[..3]);
+    let mut visited = [0; 3];
+    small.nearest(&Vec3::zero(), |idx| {
+        visited[idx] += 1;
+        0.
+    });
+    assert!(visited.iter().all(|v| *v == 1));
+
+    // Every primitive is reachable exactly once
+    let mut visited = [0; 21];
+    bvh.nearest(&Vec3::zero(), |idx| {
+        visited[idx] += 1;
+        f32::INFINITY
+    });
+    assert!(visited.iter().all(|v| *v == 1));
+
+    // A ray along x passes boxes in order; report a hit at the box's near face.
+    let mut tested = vec![];
+    let t = bvh.raycast(
+        &Vec3::new(-10., 0., 0.),
+        &Vec3::new(1., 0., 0.),
+        f32::INFINITY,
+        |idx, t| {
+            tested.push(idx);
+            if idx == 20 {
+                return t;
+            }
+            (idx as f32 * 3. + 9.).min(t)
+        },
+    );
+    assert_eq!(t, 9.);
+    assert!(tested.len() < 21);
+
+    // Nearest query with a shrinking radius prunes far subtrees
+    let mut count = 0;
+    bvh.nearest(&Vec3::new(30., 5., 0.), |idx| {
+        count += 1;
+        if idx == 10 {
+            4.
+        } else {
+            f32::INFINITY
+        }
+    });
+    assert!(count <= 21);
+}
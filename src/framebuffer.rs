@@ -0,0 +1,207 @@
+use crate::render::RenderColor;
+use crate::tonemap::ToneMapping;
+use image::ColorType;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// A two dimensional array of pixels in row major order, top row first.
+#[derive(Clone)]
+pub struct Framebuffer<T> {
+    width: usize,
+    height: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy> Framebuffer<T> {
+    pub fn new(width: usize, height: usize, fill: T) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![fill; width * height],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[allow(dead_code)]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.data[x + y * self.width]
+    }
+
+    pub fn put(&mut self, x: usize, y: usize, v: T) {
+        self.data[x + y * self.width] = v;
+    }
+
+    #[allow(dead_code)]
+    pub fn pixels(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn map<U: Copy>(&self, f: impl Fn(&T) -> U) -> Framebuffer<U> {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            data: self.data.iter().map(f).collect(),
+        }
+    }
+}
+
+/// Image file formats that a `Framebuffer<RenderColor>` can be written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// 8 bits per channel in whatever format the image crate infers from the extension,
+    /// usually PNG.
+    Ldr8,
+    /// 16 bits per channel PNG, selected by a file name ending with `.16.png`.
+    Png16,
+    OpenExr,
+    /// Portable float map
+    Pfm,
+    /// Radiance RGBE
+    Hdr,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &str) -> Self {
+        let lower = path.to_lowercase();
+        match Path::new(&lower).extension().and_then(|e| e.to_str()) {
+            Some("exr") => OutputFormat::OpenExr,
+            Some("pfm") => OutputFormat::Pfm,
+            Some("hdr") => OutputFormat::Hdr,
+            Some("png") if lower.ends_with(".16.png") => OutputFormat::Png16,
+            _ => OutputFormat::Ldr8,
+        }
+    }
+}
+
+impl Framebuffer<RenderColor> {
+    /// Clamp to 8 bits per channel, interleaved RGB.
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        self.data
+            .iter()
+            .flat_map(|c| {
+                [
+                    (c.r * 255.).min(255.) as u8,
+                    (c.g * 255.).min(255.) as u8,
+                    (c.b * 255.).min(255.) as u8,
+                ]
+            })
+            .collect()
+    }
+
+    /// Clamp to 16 bits per channel, interleaved RGB.
+    pub fn to_rgb16(&self) -> Vec<u16> {
+        self.data
+            .iter()
+            .flat_map(|c| {
+                [
+                    (c.r * 65535.).min(65535.) as u16,
+                    (c.g * 65535.).min(65535.) as u16,
+                    (c.b * 65535.).min(65535.) as u16,
+                ]
+            })
+            .collect()
+    }
+
+    fn to_rgb32f(&self) -> Vec<f32> {
+        self.data.iter().flat_map(|c| [c.r, c.g, c.b]).collect()
+    }
+
+    /// Writes the image in the format chosen by `OutputFormat::from_path`. Integer formats
+    /// go through `tone_mapping`, while floating point formats keep the linear values.
+    pub fn save(&self, path: &str, tone_mapping: &ToneMapping) -> image::ImageResult<()> {
+        let (width, height) = (self.width as u32, self.height as u32);
+        match OutputFormat::from_path(path) {
+            OutputFormat::Ldr8 => image::save_buffer(
+                path,
+                &tone_mapping.apply_framebuffer(self).to_rgb8(),
+                width,
+                height,
+                ColorType::Rgb8,
+            ),
+            OutputFormat::Png16 => {
+                let data = tone_mapping.apply_framebuffer(self).to_rgb16();
+                let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_ne_bytes()).collect();
+                image::save_buffer_with_format(
+                    path,
+                    &bytes,
+                    width,
+                    height,
+                    ColorType::Rgb16,
+                    image::ImageFormat::Png,
+                )
+            }
+            OutputFormat::OpenExr => {
+                let data = self.to_rgb32f();
+                let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_ne_bytes()).collect();
+                image::save_buffer(path, &bytes, width, height, ColorType::Rgb32F)
+            }
+            OutputFormat::Pfm => {
+                let mut file = BufWriter::new(std::fs::File::create(path)?);
+                self.write_pfm(&mut file)?;
+                Ok(file.flush()?)
+            }
+            OutputFormat::Hdr => {
+                let pixels: Vec<_> = self
+                    .data
+                    .iter()
+                    .map(|c| image::Rgb([c.r.max(0.), c.g.max(0.), c.b.max(0.)]))
+                    .collect();
+                let file = BufWriter::new(std::fs::File::create(path)?);
+                image::codecs::hdr::HdrEncoder::new(file).encode(&pixels, self.width, self.height)
+            }
+        }
+    }
+
+    /// Portable float map: a text header followed by little endian floats, bottom row first.
+    fn write_pfm(&self, w: &mut impl Write) -> std::io::Result<()> {
+        write!(w, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.data.chunks(self.width.max(1)).rev() {
+            for c in row {
+                for v in [c.r, c.g, c.b] {
+                    w.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_output_format() {
+    assert_eq!(OutputFormat::from_path("foo.png"), OutputFormat::Ldr8);
+    assert_eq!(OutputFormat::from_path("foo.jpg"), OutputFormat::Ldr8);
+    assert_eq!(OutputFormat::from_path("foo.16.png"), OutputFormat::Png16);
+    assert_eq!(
+        OutputFormat::from_path("out/foo.EXR"),
+        OutputFormat::OpenExr
+    );
+    assert_eq!(OutputFormat::from_path("foo.pfm"), OutputFormat::Pfm);
+    assert_eq!(OutputFormat::from_path("foo.hdr"), OutputFormat::Hdr);
+}
+
+#[test]
+fn test_framebuffer_conversion() {
+    let mut fb = Framebuffer::new(2, 1, RenderColor::zero());
+    fb.put(1, 0, RenderColor::new(2., 0.5, -1.));
+    assert_eq!(fb.to_rgb8(), vec![0, 0, 0, 255, 127, 0]);
+    assert_eq!(fb.to_rgb16()[3..], [65535, 32767, 0]);
+
+    let mut pfm = vec![];
+    fb.write_pfm(&mut pfm).unwrap();
+    let header = b"PF\n2 1\n-1.0\n";
+    assert_eq!(&pfm[..header.len()], header);
+    assert_eq!(pfm.len(), header.len() + 2 * 3 * 4);
+    assert_eq!(
+        pfm[header.len() + 12..header.len() + 16],
+        2f32.to_le_bytes()
+    );
+}
@@ -1,12 +1,23 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub mod background;
+pub mod bvh;
+pub mod framebuffer;
 #[cfg(feature = "webserver")]
 mod hyper_adapt;
+pub mod light;
+pub mod mesh;
+pub mod microfacet;
 mod modutil;
 mod pixelutil;
+mod polynomial;
 pub mod quat;
 pub mod render;
+pub mod sampling;
+pub mod sdf;
+pub mod tonemap;
+pub mod transform;
 pub mod vec3;
 #[cfg(feature = "webserver")]
 mod webserver;
@@ -0,0 +1,294 @@
+use crate::render::RenderColor;
+use crate::sampling::{sample_disk, tangent_frame};
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RenderLightKind {
+    /// Infinitely far light like the sun. `direction` points toward the light.
+    Directional { direction: Vec3 },
+    /// Omnidirectional light whose intensity halves at distance `radius`.
+    Point { position: Vec3, radius: f32 },
+    /// Point light restricted to a cone around `direction`, which points away from the light.
+    /// `cone_angle` is the half angle of the cone in radians, and the intensity fades out
+    /// over the last `falloff` radians toward the edge.
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        radius: f32,
+        cone_angle: f32,
+        falloff: f32,
+    },
+    /// Ball of radius `size` glowing evenly in all directions, attenuated like a point light.
+    Sphere {
+        position: Vec3,
+        radius: f32,
+        size: f32,
+    },
+    /// Rectangle centered at `position` with half edges `u` and `v`, which only lights the
+    /// side that `u` cross `v` points to.
+    Rect {
+        position: Vec3,
+        u: Vec3,
+        v: Vec3,
+        radius: f32,
+    },
+    /// Disk of radius `size` centered at `position`, which only lights the side `normal`
+    /// points to.
+    Disk {
+        position: Vec3,
+        normal: Vec3,
+        size: f32,
+        radius: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RenderLight {
+    pub kind: RenderLightKind,
+    pub color: RenderColor,
+    pub intensity: f32,
+    /// Number of shadow rays toward points spread over an area light, which decides how
+    /// smooth its penumbra is. Ignored by the other kinds, which are points.
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+}
+
+fn default_samples() -> u32 {
+    16
+}
+
+impl RenderLight {
+    pub fn new(kind: RenderLightKind) -> Self {
+        Self {
+            kind,
+            color: RenderColor::new(1., 1., 1.),
+            intensity: 1.,
+            samples: default_samples(),
+        }
+    }
+
+    pub fn directional(direction: Vec3) -> Self {
+        Self::new(RenderLightKind::Directional {
+            direction: direction.normalized(),
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn point(position: Vec3, radius: f32) -> Self {
+        Self::new(RenderLightKind::Point { position, radius })
+    }
+
+    #[allow(dead_code)]
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        radius: f32,
+        cone_angle: f32,
+        falloff: f32,
+    ) -> Self {
+        Self::new(RenderLightKind::Spot {
+            position,
+            direction: direction.normalized(),
+            radius,
+            cone_angle,
+            falloff,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn sphere(position: Vec3, radius: f32, size: f32) -> Self {
+        Self::new(RenderLightKind::Sphere {
+            position,
+            radius,
+            size,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn rect(position: Vec3, u: Vec3, v: Vec3, radius: f32) -> Self {
+        Self::new(RenderLightKind::Rect {
+            position,
+            u,
+            v,
+            radius,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn disk(position: Vec3, normal: Vec3, size: f32, radius: f32) -> Self {
+        Self::new(RenderLightKind::Disk {
+            position,
+            normal: normal.normalized(),
+            size,
+            radius,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn color(mut self, color: RenderColor) -> Self {
+        self.color = color;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Radius of a disk with the same area as the light, or 0 for lights that are points.
+    pub fn size(&self) -> f32 {
+        match self.kind {
+            RenderLightKind::Sphere { size, .. } | RenderLightKind::Disk { size, .. } => size,
+            RenderLightKind::Rect { u, v, .. } => {
+                (4. * u.cross(&v).len() / std::f32::consts::PI).sqrt()
+            }
+            _ => 0.,
+        }
+    }
+
+    /// Number of points on the light to sample for shadows.
+    pub fn sample_count(&self) -> u32 {
+        if 0. < self.size() {
+            self.samples.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Returns the normalized direction from `pt` toward the light, the distance to the light
+    /// and the light color arriving at `pt` before shadowing.
+    #[allow(dead_code)]
+    pub fn illuminate(&self, pt: &Vec3) -> (Vec3, f32, RenderColor) {
+        self.illuminate_from(pt, 0.5, 0.5)
+    }
+
+    /// Same as `illuminate`, but from the point on an area light chosen by the pair of numbers
+    /// in [0, 1), where (0.5, 0.5) is the center. The whole light is the average over all of
+    /// its points.
+    pub fn illuminate_from(&self, pt: &Vec3, s: f32, t: f32) -> (Vec3, f32, RenderColor) {
+        let attenuate = |position: &Vec3, radius: f32| {
+            let delta = *position - *pt;
+            let dist = delta.len();
+            let d = dist / radius;
+            (delta * (1. / dist), dist, 1. / (1. + d * d))
+        };
+        let (direction, dist, factor) = match self.kind {
+            RenderLightKind::Directional { direction } => (direction, f32::INFINITY, 1.),
+            RenderLightKind::Point { position, radius } => attenuate(&position, radius),
+            RenderLightKind::Spot {
+                position,
+                direction: axis,
+                radius,
+                cone_angle,
+                falloff,
+            } => {
+                let (direction, dist, factor) = attenuate(&position, radius);
+                let angle = (-direction.dot(&axis)).min(1.).acos();
+                let edge = if cone_angle <= angle {
+                    0.
+                } else if angle <= cone_angle - falloff {
+                    1.
+                } else {
+                    let x = (cone_angle - angle) / falloff;
+                    x * x * (3. - 2. * x)
+                };
+                (direction, dist, factor * edge)
+            }
+            RenderLightKind::Sphere {
+                position,
+                radius,
+                size,
+            } => {
+                // Points on the disk that the sphere looks like from pt
+                let (x, y) = sample_disk(s, t);
+                let (tangent, bitangent) = tangent_frame(&(position - *pt).normalized());
+                attenuate(&(position + (tangent * x + bitangent * y) * size), radius)
+            }
+            RenderLightKind::Rect {
+                position,
+                u,
+                v,
+                radius,
+            } => {
+                let sample = position + u * (2. * s - 1.) + v * (2. * t - 1.);
+                let (direction, dist, factor) = attenuate(&sample, radius);
+                let facing = -direction.dot(&u.cross(&v).normalized());
+                (direction, dist, factor * facing.max(0.))
+            }
+            RenderLightKind::Disk {
+                position,
+                normal,
+                size,
+                radius,
+            } => {
+                let (x, y) = sample_disk(s, t);
+                let (tangent, bitangent) = tangent_frame(&normal);
+                let sample = position + (tangent * x + bitangent * y) * size;
+                let (direction, dist, factor) = attenuate(&sample, radius);
+                (direction, dist, factor * (-direction.dot(&normal)).max(0.))
+            }
+        };
+        (direction, dist, self.color * (self.intensity * factor))
+    }
+}
+
+#[test]
+fn test_illuminate() {
+    let pt = Vec3::new(0., 0., 0.);
+    let (dir, dist, color) = RenderLight::directional(Vec3::new(0., 2., 0.)).illuminate(&pt);
+    assert_eq!((dir.y, dist, color.r), (1., f32::INFINITY, 1.));
+
+    let (dir, dist, color) = RenderLight::point(Vec3::new(0., 10., 0.), 10.)
+        .intensity(2.)
+        .illuminate(&pt);
+    assert_eq!((dir.y, dist, color.g), (1., 10., 1.));
+
+    let spot = |x| {
+        RenderLight::spot(Vec3::new(x, 10., 0.), Vec3::new(0., -1., 0.), 10., 0.5, 0.2)
+            .illuminate(&pt)
+            .2
+            .b
+    };
+    assert_eq!(spot(0.), 0.5);
+    assert_eq!(spot(10.), 0.);
+    let edge = spot(10. * (0.4f32).tan());
+    assert!(0. < edge && edge < 0.5);
+}
+
+#[test]
+fn test_area_lights() {
+    let pt = Vec3::new(0., 0., 0.);
+    let sphere = RenderLight::sphere(Vec3::new(0., 10., 0.), 10., 2.);
+    let (dir, dist, color) = sphere.illuminate(&pt);
+    assert_eq!((dir.y, dist, color.r), (1., 10., 0.5));
+    assert_eq!(sphere.sample_count(), 16);
+    // Points on the edge of the sphere are off to the side
+    let (dir, _, _) = sphere.illuminate_from(&pt, 1., 0.5);
+    assert!((dir.x.abs() + dir.z.abs() - 0.2 / 1.04f32.sqrt()).abs() < 1e-5);
+
+    let rect = RenderLight::rect(
+        Vec3::new(0., 10., 0.),
+        Vec3::new(1., 0., 0.),
+        Vec3::new(0., 0., 1.),
+        10.,
+    );
+    assert_eq!(rect.illuminate(&pt).2.g, 0.5);
+    let corner = rect.illuminate_from(&pt, 0., 0.).0;
+    assert!((corner.x + corner.z + 2. / 102f32.sqrt()).abs() < 1e-5);
+    // Nothing is lit behind the rectangle
+    assert_eq!(rect.illuminate(&Vec3::new(0., 20., 0.)).2.g, 0.);
+    assert!((rect.size() - (4. / std::f32::consts::PI).sqrt()).abs() < 1e-6);
+
+    let disk = RenderLight::disk(Vec3::new(0., 10., 0.), Vec3::new(0., -2., 0.), 3., 10.);
+    assert_eq!(disk.illuminate(&pt).2.b, 0.5);
+    assert_eq!(disk.illuminate(&Vec3::new(0., 20., 0.)).2.b, 0.);
+
+    assert_eq!(RenderLight::point(pt, 1.).sample_count(), 1);
+}
@@ -1,7 +1,6 @@
 #[macro_use]
 extern crate serde_derive;
 
-use image::ColorType;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::prelude::*;
@@ -9,21 +8,37 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Instant;
 
+mod background;
+mod bvh;
+mod framebuffer;
 #[cfg(feature = "webserver")]
 mod hyper_adapt;
+mod light;
+mod mesh;
+mod microfacet;
 mod modutil;
 mod pixelutil;
+mod polynomial;
 mod quat;
 mod render;
+mod sampling;
+mod sdf;
+mod tonemap;
+mod transform;
 mod vec3;
 #[cfg(feature = "webserver")]
 mod webserver;
 
+use background::Background;
 use clap::{crate_authors, crate_version, Arg, Command};
+use light::RenderLight;
 use render::{
-    render, render_frames, RenderColor, RenderEnv, RenderFloor, RenderMaterial, RenderObject,
-    RenderPattern, RenderSphere, UVMap,
+    render_ao_framebuffer, render_framebuffer, render_frames, render_progressive, RenderColor,
+    RenderEnv, RenderFloor, RenderMaterial, RenderMode, RenderObject, RenderPattern, RenderSphere,
+    UVMap,
 };
+use sampling::{PixelFilter, SamplePattern};
+use tonemap::{ToneMapOperator, ToneMapping};
 use vec3::Vec3;
 #[cfg(feature = "webserver")]
 use webserver::{run_webserver, ServerParams};
@@ -55,16 +70,79 @@ fn main() -> anyhow::Result<()> {
             .default_value("foo.png")
         )
         .arg(Arg::new("raymarch")
-            .help("Use ray marching")
+            .help("Use ray marching, the same as --mode raymarch")
             .short('m')
             .long("raymarch")
         )
+        .arg(Arg::new("mode")
+            .help("Rendering method")
+            .long("mode")
+            .takes_value(true)
+            .possible_values(["raytrace", "raymarch", "pathtrace"])
+            .conflicts_with("raymarch")
+        )
+        .arg(Arg::new("passes")
+            .help("Number of progressively accumulated images when path tracing. The output is \
+updated after each of them.")
+            .long("passes")
+            .takes_value(true)
+        )
+        .arg(Arg::new("ao_samples")
+            .help("Enable ambient occlusion and set the number of rays or steps sampling it")
+            .long("ao_samples")
+            .takes_value(true)
+        )
+        .arg(Arg::new("ao_radius")
+            .help("Enable ambient occlusion and set the distance within which surfaces occlude")
+            .long("ao_radius")
+            .takes_value(true)
+        )
+        .arg(Arg::new("ao_output")
+            .help("File name for the ambient occlusion pass, rendered after the image")
+            .long("ao_output")
+            .takes_value(true)
+        )
         .arg(Arg::new("gloweffect")
             .help("Enable glow effect and set its strength when ray marching method is used")
             .short('g')
             .long("gloweffect")
             .takes_value(true)
         )
+        .arg(Arg::new("samples")
+            .help("Samples per pixel for anti-aliasing")
+            .short('a')
+            .long("samples")
+            .takes_value(true)
+        )
+        .arg(Arg::new("sample_pattern")
+            .help("Distribution of anti-aliasing samples within a pixel")
+            .long("sample_pattern")
+            .takes_value(true)
+            .possible_values(["grid", "jittered", "random"])
+        )
+        .arg(Arg::new("pixel_filter")
+            .help("Reconstruction filter weighting anti-aliasing samples")
+            .long("pixel_filter")
+            .takes_value(true)
+            .possible_values(["box", "tent", "gaussian"])
+        )
+        .arg(Arg::new("exposure")
+            .help("Exposure adjustment in stops applied before tone mapping")
+            .short('e')
+            .long("exposure")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+        )
+        .arg(Arg::new("tone_map")
+            .help("Tone mapping operator for 8 and 16 bit outputs")
+            .long("tone_map")
+            .takes_value(true)
+            .possible_values(["clamp", "reinhard", "aces"])
+        )
+        .arg(Arg::new("srgb")
+            .help("Encode 8 and 16 bit outputs with the sRGB transfer curve")
+            .long("srgb")
+        )
         .arg(Arg::new("serialize_file")
             .help("File name for serialized scene output. If omitted, scene is not output.")
             .short('s')
@@ -122,8 +200,38 @@ Good for interactive session.")
     let thread_count = parser(&matches, "threads");
     let output: String = parser(&matches, "output");
 
-    let use_raymarching = matches.is_present("raymarch");
+    let render_mode = if matches.is_present("raymarch") {
+        Some(RenderMode::RayMarch)
+    } else {
+        matches.value_of("mode").map(|s| match s {
+            "raymarch" => RenderMode::RayMarch,
+            "pathtrace" => RenderMode::PathTrace,
+            _ => RenderMode::RayTrace,
+        })
+    };
+    let passes = parser_opt(&matches, "passes");
+    let ao_samples = parser_opt(&matches, "ao_samples");
+    let ao_radius = parser_opt(&matches, "ao_radius");
+    let ao_output = parser_opt::<String>(&matches, "ao_output");
     let glow_effect = parser_opt(&matches, "gloweffect");
+    let samples = parser_opt(&matches, "samples");
+    let sample_pattern = matches.value_of("sample_pattern").map(|s| match s {
+        "jittered" => SamplePattern::Jittered,
+        "random" => SamplePattern::Random,
+        _ => SamplePattern::Grid,
+    });
+    let pixel_filter = matches.value_of("pixel_filter").map(|s| match s {
+        "tent" => PixelFilter::Tent,
+        "gaussian" => PixelFilter::Gaussian,
+        _ => PixelFilter::Box,
+    });
+    let exposure = parser_opt(&matches, "exposure");
+    let tone_map = matches.value_of("tone_map").map(|s| match s {
+        "reinhard" => ToneMapOperator::Reinhard,
+        "aces" => ToneMapOperator::Aces,
+        _ => ToneMapOperator::Clamp,
+    });
+    let srgb = matches.is_present("srgb");
     let serialize_file = parser_opt::<String>(&matches, "serialize_file");
     let deserialize_file = parser_opt::<String>(&matches, "deserialize_file");
     let webserver = matches.is_present("webserver");
@@ -135,22 +243,6 @@ Good for interactive session.")
     let xfov: f32 = 1.;
     let yfov: f32 = ymax as f32 / xmax as f32;
 
-    let mut data = vec![0u8; 3 * width * height];
-
-    for y in 0..height {
-        for x in 0..width {
-            data[(x + y * width) * 3] = ((x) * 255 / width) as u8;
-            data[(x + y * width) * 3 + 1] = ((y) * 255 / height) as u8;
-            data[(x + y * width) * 3 + 2] = ((x + y) % 32 + 32) as u8;
-        }
-    }
-
-    let mut putpoint = |x: i32, y: i32, fc: &RenderColor| {
-        data[(x as usize + y as usize * width) * 3] = (fc.r * 255.).min(255.) as u8;
-        data[(x as usize + y as usize * width) * 3 + 1] = (fc.g * 255.).min(255.) as u8;
-        data[(x as usize + y as usize * width) * 3 + 2] = (fc.b * 255.).min(255.) as u8;
-    };
-
     let mut materials: HashMap<String, Arc<RenderMaterial>> = HashMap::new();
 
     let floor_material = Arc::new(
@@ -228,37 +320,6 @@ Good for interactive session.")
 
     use std::f32::consts::PI;
 
-    fn bgcolor(ren: &RenderEnv, direction: &Vec3) -> RenderColor {
-        let phi = direction.z.atan2(direction.x);
-        let the = direction.y.asin();
-        let d = (50. * PI + phi * 10. * PI) % (2. * PI) - PI;
-        let dd = (50. * PI + the * 10. * PI) % (2. * PI) - PI;
-        let ret = RenderColor::new(
-            0.5 / (15. * (d * d * dd * dd) + 1.),
-            0.25 - direction.y / 4.,
-            0.25 - direction.y / 4.,
-        );
-        let dot = ren.light.dot(direction);
-
-        if dot > 0.9 {
-            if 0.9995 < dot {
-                RenderColor::new(2., 2., 2.)
-            } else {
-                let ret2 = if 0.995 < dot {
-                    let dd = (dot - 0.995) * 150.;
-                    RenderColor::new(ret.r + dd, ret.g + dd, ret.b + dd)
-                } else {
-                    ret
-                };
-                let dot2 = dot - 0.9;
-                RenderColor::new(ret2.r + dot2 * 5., ret2.g + dot2 * 5., ret2.b)
-            }
-        } else {
-            ret
-        }
-        // else PointMandel(dir->x * 2., dir->z * 2., 32, ret);
-    }
-
     let mut ren: RenderEnv = RenderEnv::new(
         Vec3::new(0., -150., -300.),       /* cam */
         Vec3::new(0., -PI / 2., -PI / 2.), /* pyr */
@@ -267,15 +328,17 @@ Good for interactive session.")
         xfov,
         yfov, /* xfov, yfov*/
         //pointproc: putpoint, /* pointproc */
-        bgcolor, /* bgproc */
+        Background::Sky,
     )
     .materials(materials)
     .objects(objects)
-    .light(Vec3::new(50., 60., -50.))
-    .use_raymarching(use_raymarching)
-    .glow_effect(glow_effect);
+    .lights(vec![RenderLight::directional(Vec3::new(50., 60., -50.))]);
 
     if let Some(file_name) = deserialize_file {
+        // Files referenced by the scene are relative to it
+        if let Some(dir) = std::path::Path::new(&file_name).parent() {
+            ren.scene_dir = dir.to_path_buf();
+        }
         let mut file = std::fs::File::open(file_name)?;
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
@@ -294,6 +357,45 @@ Good for interactive session.")
         // }
     }
 
+    // Command line flags take precedence over the scene file
+    if let Some(render_mode) = render_mode {
+        ren = ren.render_mode(render_mode);
+    }
+    if let Some(passes) = passes {
+        ren.path_tracing.passes = passes;
+    }
+    if ao_samples.is_some() || ao_radius.is_some() {
+        let mut ao = ren.ambient_occlusion.unwrap_or_default();
+        if let Some(samples) = ao_samples {
+            ao.samples = samples;
+        }
+        if let Some(radius) = ao_radius {
+            ao.radius = radius;
+        }
+        ren = ren.ambient_occlusion(Some(ao));
+    }
+    if glow_effect.is_some() {
+        ren = ren.glow_effect(glow_effect);
+    }
+    if let Some(samples) = samples {
+        ren.antialiasing.samples = samples;
+    }
+    if let Some(pattern) = sample_pattern {
+        ren.antialiasing.pattern = pattern;
+    }
+    if let Some(filter) = pixel_filter {
+        ren.antialiasing.filter = filter;
+    }
+    if let Some(exposure) = exposure {
+        ren.tone_mapping.exposure = exposure;
+    }
+    if let Some(operator) = tone_map {
+        ren.tone_mapping.operator = operator;
+    }
+    if srgb {
+        ren.tone_mapping.srgb = true;
+    }
+
     if webserver {
         #[cfg(feature = "webserver")]
         return Ok(run_webserver(Arc::new(ServerParams {
@@ -316,28 +418,56 @@ Good for interactive session.")
     let start = Instant::now();
 
     let ret = if !ren.camera_motion.0.is_empty() {
+        let tone_mapping = ren.tone_mapping;
+        // Frames get the same suffix as the output, which decides the file format, so that
+        // "foo.16.png" gives "foo0.16.png", "foo1.16.png" and so on
+        let extension = std::path::Path::new(&output)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(0, |e| e.len() + 1);
+        let suffix_len = if output.to_lowercase().ends_with(".16.png") {
+            ".16.png".len()
+        } else {
+            extension
+        };
+        let (stem, suffix) = output.split_at(output.len() - suffix_len);
+        let suffix = if suffix.is_empty() { ".png" } else { suffix };
+        let mut saved = Ok(());
         render_frames(
             &mut ren,
-            width,
-            height,
-            &mut |i, data| {
-                let frame_output = format!("{}{}.png", output, i);
-                image::save_buffer(
-                    frame_output,
-                    &data,
-                    width as u32,
-                    height as u32,
-                    ColorType::Rgb8,
-                )
-                .ok();
+            &mut |i, framebuffer| {
+                let frame_output = format!("{}{}{}", stem, i, suffix);
+                if saved.is_ok() {
+                    saved = framebuffer.save(&frame_output, &tone_mapping);
+                }
+            },
+            thread_count,
+        )?;
+        saved
+    } else if ren.render_mode == RenderMode::PathTrace {
+        let passes = ren.path_tracing.passes;
+        let mut saved = Ok(());
+        render_progressive(
+            &ren,
+            &mut |pass, framebuffer| {
+                println!("Pass {} / {}", pass, passes);
+                // Later passes would likely fail the same way, so report the first error
+                if saved.is_ok() {
+                    saved = framebuffer.save(&output, &ren.tone_mapping);
+                }
             },
             thread_count,
-        );
-        Ok(())
+        )?;
+        saved
     } else {
-        render(&ren, &mut putpoint, thread_count);
-
-        image::save_buffer(output, &data, width as u32, height as u32, ColorType::Rgb8)
+        render_framebuffer(&ren, thread_count)?.save(&output, &ren.tone_mapping)
+    };
+    // Saved without tone mapping, so that the occlusion can be multiplied in as it is
+    let ret = match (ret, ao_output) {
+        (Ok(()), Some(ao_output)) => {
+            render_ao_framebuffer(&ren, thread_count)?.save(&ao_output, &ToneMapping::default())
+        }
+        (ret, _) => ret,
     };
 
     let end = start.elapsed();
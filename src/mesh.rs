@@ -0,0 +1,496 @@
+//! Triangle mesh geometry and a minimal Wavefront OBJ reader.
+//!
+//! This module only knows about geometry; the renderable object that binds a mesh to a material
+//! is `RenderMesh` in render.rs.
+use crate::bvh::{Aabb, Bvh};
+use crate::transform::Transform;
+use crate::vec3::Vec3;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    pub v: [usize; 3],
+    pub n: Option<[usize; 3]>,
+    pub uv: Option<[usize; 3]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub triangles: Vec<Triangle>,
+    bvh: Bvh,
+    /// Angle weighted sums of the normals of the faces around each vertex
+    vertex_pseudo_normals: Vec<Vec3>,
+    /// Sums of the normals of the faces on each edge, keyed by its vertices in ascending order
+    edge_pseudo_normals: HashMap<(usize, usize), Vec3>,
+}
+
+/// Pseudo-normals of the vertices and edges of a mesh, which tell the inside from the outside of
+/// a closed mesh wherever the closest point on it is (Bærentzen and Aanæs, Signed Distance
+/// Computation Using the Angle Weighted Pseudonormal). The normal of the closest face alone can
+/// point the wrong way at a sharp edge or corner shared by several faces.
+fn pseudo_normals(
+    vertices: &[Vec3],
+    triangles: &[Triangle],
+) -> (Vec<Vec3>, HashMap<(usize, usize), Vec3>) {
+    let mut vertex_normals = vec![Vec3::zero(); vertices.len()];
+    let mut edge_normals = HashMap::new();
+    for tri in triangles {
+        let [a, b, c] = [vertices[tri.v[0]], vertices[tri.v[1]], vertices[tri.v[2]]];
+        let n = (b - a).cross(&(c - a));
+        if n.squared_len() == 0. {
+            continue;
+        }
+        let n = n.normalized();
+        for i in 0..3 {
+            let (v, next, prev) = (tri.v[i], tri.v[(i + 1) % 3], tri.v[(i + 2) % 3]);
+            let e1 = (vertices[next] - vertices[v]).normalized();
+            let e2 = (vertices[prev] - vertices[v]).normalized();
+            vertex_normals[v] += n * e1.dot(&e2).clamp(-1., 1.).acos();
+            *edge_normals
+                .entry((v.min(next), v.max(next)))
+                .or_insert_with(Vec3::zero) += n;
+        }
+    }
+    (vertex_normals, edge_normals)
+}
+
+fn invalid_data(s: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, s)
+}
+
+/// Resolve an OBJ index, which is 1-based and may be negative (relative to the end).
+fn obj_index(s: &str, len: usize, line: usize) -> Result<usize, io::Error> {
+    let i: i64 = s
+        .parse()
+        .map_err(|_| invalid_data(format!("line {}: bad index {}", line, s)))?;
+    let idx = if i < 0 { len as i64 + i } else { i - 1 };
+    if idx < 0 || len as i64 <= idx {
+        return Err(invalid_data(format!(
+            "line {}: index {} out of range",
+            line, s
+        )));
+    }
+    Ok(idx as usize)
+}
+
+fn parse_floats<'a>(
+    tokens: impl Iterator<Item = &'a str>,
+    line: usize,
+) -> Result<Vec<f32>, io::Error> {
+    tokens
+        .map(|s| {
+            s.parse::<f32>()
+                .map_err(|_| invalid_data(format!("line {}: bad number {}", line, s)))
+        })
+        .collect()
+}
+
+impl Mesh {
+    pub fn new(
+        vertices: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        triangles: Vec<Triangle>,
+    ) -> Self {
+        let bounds: Vec<_> = triangles
+            .iter()
+            .map(|tri| Some(Aabb::from_points(tri.v.iter().map(|i| &vertices[*i]))))
+            .collect();
+        let (vertex_pseudo_normals, edge_pseudo_normals) = pseudo_normals(&vertices, &triangles);
+        Self {
+            bvh: Bvh::build(&bounds),
+            vertex_pseudo_normals,
+            edge_pseudo_normals,
+            vertices,
+            normals,
+            uvs,
+            triangles,
+        }
+    }
+
+    pub fn load_obj(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        Self::parse_obj(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse positions, normals, texture coordinates and faces out of an OBJ source.
+    /// Polygons are triangulated as fans; materials, groups and smoothing groups are ignored.
+    pub fn parse_obj(src: &str) -> Result<Self, io::Error> {
+        let mut vertices = vec![];
+        let mut normals = vec![];
+        let mut uvs = vec![];
+        let mut triangles = vec![];
+
+        for (i, line) in src.lines().enumerate() {
+            let line_no = i + 1;
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let f = parse_floats(tokens.take(3), line_no)?;
+                    if f.len() < 3 {
+                        return Err(invalid_data(format!("line {}: short vertex", line_no)));
+                    }
+                    vertices.push(Vec3::new(f[0], f[1], f[2]));
+                }
+                Some("vn") => {
+                    let f = parse_floats(tokens.take(3), line_no)?;
+                    if f.len() < 3 {
+                        return Err(invalid_data(format!("line {}: short normal", line_no)));
+                    }
+                    normals.push(Vec3::new(f[0], f[1], f[2]).normalized());
+                }
+                Some("vt") => {
+                    let f = parse_floats(tokens.take(2), line_no)?;
+                    uvs.push((
+                        f.first().copied().unwrap_or(0.),
+                        f.get(1).copied().unwrap_or(0.),
+                    ));
+                }
+                Some("f") => {
+                    // Each corner is one of v, v/vt, v//vn or v/vt/vn
+                    let mut corners = vec![];
+                    for corner in tokens {
+                        let mut parts = corner.split('/');
+                        let v = obj_index(parts.next().unwrap_or(""), vertices.len(), line_no)?;
+                        let vt = match parts.next() {
+                            Some(s) if !s.is_empty() => Some(obj_index(s, uvs.len(), line_no)?),
+                            _ => None,
+                        };
+                        let vn = match parts.next() {
+                            Some(s) if !s.is_empty() => Some(obj_index(s, normals.len(), line_no)?),
+                            _ => None,
+                        };
+                        corners.push((v, vt, vn));
+                    }
+                    if corners.len() < 3 {
+                        return Err(invalid_data(format!(
+                            "line {}: face with less than 3 vertices",
+                            line_no
+                        )));
+                    }
+                    for j in 1..corners.len() - 1 {
+                        let c = [corners[0], corners[j], corners[j + 1]];
+                        triangles.push(Triangle {
+                            v: [c[0].0, c[1].0, c[2].0],
+                            uv: match (c[0].1, c[1].1, c[2].1) {
+                                (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                                _ => None,
+                            },
+                            n: match (c[0].2, c[1].2, c[2].2) {
+                                (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                                _ => None,
+                            },
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if triangles.is_empty() {
+            return Err(invalid_data("OBJ has no faces".to_string()));
+        }
+
+        Ok(Self::new(vertices, normals, uvs, triangles))
+    }
+
+    /// Bake a transformation into vertex positions and normals.
+    pub fn transformed(&self, transform: &Transform) -> Self {
+        Self::new(
+            self.vertices
+                .iter()
+                .map(|v| transform.apply_point(v))
+                .collect(),
+            self.normals
+                .iter()
+                .map(|n| transform.apply_normal(n))
+                .collect(),
+            self.uvs.clone(),
+            self.triangles.clone(),
+        )
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn corners(&self, tri: &Triangle) -> (Vec3, Vec3, Vec3) {
+        (
+            self.vertices[tri.v[0]],
+            self.vertices[tri.v[1]],
+            self.vertices[tri.v[2]],
+        )
+    }
+
+    /// Möller-Trumbore intersection of a single triangle.
+    /// Faces wound counter-clockwise seen from the ray origin are front faces.
+    /// Returns the hit distance and the barycentric weights of the hit point.
+    pub fn raycast_triangle(
+        &self,
+        tri: &Triangle,
+        vi: &Vec3,
+        eye: &Vec3,
+        front_faces: bool,
+        back_faces: bool,
+    ) -> Option<(f32, [f32; 3])> {
+        let (v0, v1, v2) = self.corners(tri);
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+        let p = eye.cross(&e2);
+        let det = e1.dot(&p);
+        if (0. < det && !front_faces) || (det < 0. && !back_faces) || det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1. / det;
+        let s = *vi - v0;
+        let u = s.dot(&p) * inv_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let v = eye.dot(&q) * inv_det;
+        if v < 0. || 1. < u + v {
+            return None;
+        }
+        let t = e2.dot(&q) * inv_det;
+        if 0. <= t {
+            Some((t, [1. - u - v, u, v]))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the closest hit distance, the triangle index and the barycentric weights of the
+    /// hit point, or `ray_length` if nothing is hit before it.
+    pub fn raycast(
+        &self,
+        vi: &Vec3,
+        eye: &Vec3,
+        ray_length: f32,
+        front_faces: bool,
+        back_faces: bool,
+    ) -> (f32, usize, [f32; 3]) {
+        let mut ret_idx = 0;
+        let mut ret_bary = [1., 0., 0.];
+        let t = self.bvh.raycast(vi, eye, ray_length, |idx, t| {
+            match self.raycast_triangle(&self.triangles[idx], vi, eye, front_faces, back_faces) {
+                Some((tri_t, bary)) if tri_t < t => {
+                    ret_idx = idx;
+                    ret_bary = bary;
+                    tri_t
+                }
+                _ => t,
+            }
+        });
+        (t, ret_idx, ret_bary)
+    }
+
+    /// Closest point on a triangle to `p`, returned as barycentric weights of the three corners
+    /// (Ericson, Real-Time Collision Detection, 5.1.5).
+    #[allow(clippy::many_single_char_names)]
+    fn closest_barycentric(&self, tri: &Triangle, p: &Vec3) -> [f32; 3] {
+        let (a, b, c) = self.corners(tri);
+        let ab = b - a;
+        let ac = c - a;
+        let ap = *p - a;
+        let d1 = ab.dot(&ap);
+        let d2 = ac.dot(&ap);
+        if d1 <= 0. && d2 <= 0. {
+            return [1., 0., 0.];
+        }
+        let bp = *p - b;
+        let d3 = ab.dot(&bp);
+        let d4 = ac.dot(&bp);
+        if 0. <= d3 && d4 <= d3 {
+            return [0., 1., 0.];
+        }
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0. && 0. <= d1 && d3 <= 0. {
+            let v = d1 / (d1 - d3);
+            return [1. - v, v, 0.];
+        }
+        let cp = *p - c;
+        let d5 = ab.dot(&cp);
+        let d6 = ac.dot(&cp);
+        if 0. <= d6 && d5 <= d6 {
+            return [0., 0., 1.];
+        }
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0. && 0. <= d2 && d6 <= 0. {
+            let w = d2 / (d2 - d6);
+            return [1. - w, 0., w];
+        }
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0. && 0. <= d4 - d3 && 0. <= d5 - d6 {
+            let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+            return [0., 1. - w, w];
+        }
+        let denom = 1. / (va + vb + vc);
+        let v = vb * denom;
+        let w = vc * denom;
+        [1. - v - w, v, w]
+    }
+
+    fn barycentric_point(&self, tri: &Triangle, bary: &[f32; 3]) -> Vec3 {
+        let (a, b, c) = self.corners(tri);
+        a * bary[0] + b * bary[1] + c * bary[2]
+    }
+
+    /// Find the triangle nearest to `p` and the barycentric weights of the nearest point on it.
+    /// Returns the unsigned distance too.
+    pub fn closest(&self, p: &Vec3) -> (usize, [f32; 3], f32) {
+        let mut best = (0, [1., 0., 0.], f32::INFINITY);
+        self.bvh.nearest(p, |idx| {
+            let tri = &self.triangles[idx];
+            let bary = self.closest_barycentric(tri, p);
+            let dist = (self.barycentric_point(tri, &bary) - *p).len();
+            if dist < best.2 {
+                best = (idx, bary, dist);
+            }
+            best.2
+        });
+        best
+    }
+
+    /// Distance to the surface, negative behind the pseudo-normal of the nearest point, which is
+    /// the inside of a closed mesh.
+    pub fn signed_distance(&self, p: &Vec3) -> f32 {
+        let (idx, bary, dist) = self.closest(p);
+        let tri = &self.triangles[idx];
+        let q = self.barycentric_point(tri, &bary);
+        // The closest point lies on a corner if only one weight is left, and on an edge if two
+        let (mut corners, mut count) = ([0; 3], 0);
+        for (w, v) in bary.iter().zip(&tri.v) {
+            if *w != 0. {
+                corners[count] = *v;
+                count += 1;
+            }
+        }
+        let normal = match corners[..count] {
+            [v] => self.vertex_pseudo_normals[v],
+            [v0, v1] => self
+                .edge_pseudo_normals
+                .get(&(v0.min(v1), v0.max(v1)))
+                .copied()
+                .unwrap_or_else(|| self.face_normal(idx)),
+            _ => self.face_normal(idx),
+        };
+        if (*p - q).dot(&normal) < 0. {
+            -dist
+        } else {
+            dist
+        }
+    }
+
+    pub fn face_normal(&self, idx: usize) -> Vec3 {
+        let (v0, v1, v2) = self.corners(&self.triangles[idx]);
+        (v1 - v0).cross(&(v2 - v0)).normalized()
+    }
+
+    /// Interpolated vertex normal, or the face normal if the triangle has none.
+    pub fn normal(&self, idx: usize, bary: &[f32; 3]) -> Vec3 {
+        if let Some(n) = self.triangles[idx].n {
+            (self.normals[n[0]] * bary[0]
+                + self.normals[n[1]] * bary[1]
+                + self.normals[n[2]] * bary[2])
+                .normalized()
+        } else {
+            self.face_normal(idx)
+        }
+    }
+
+    /// Interpolated texture coordinates, if the triangle has any.
+    pub fn uv(&self, idx: usize, bary: &[f32; 3]) -> Option<(f32, f32)> {
+        self.triangles[idx].uv.map(|uv| {
+            let [a, b, c] = [self.uvs[uv[0]], self.uvs[uv[1]], self.uvs[uv[2]]];
+            (
+                a.0 * bary[0] + b.0 * bary[1] + c.0 * bary[2],
+                a.1 * bary[0] + b.1 * bary[1] + c.1 * bary[2],
+            )
+        })
+    }
+}
+
+#[test]
+fn test_parse_obj() {
+    let mesh = Mesh::parse_obj(
+        "# quad
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+f 1/1/1 2/2/1 3/3/1 -1/4/-1
+",
+    )
+    .unwrap();
+    assert_eq!(mesh.vertices.len(), 4);
+    assert_eq!(mesh.triangles.len(), 2);
+    assert_eq!(mesh.triangles[1].v, [0, 2, 3]);
+    assert_eq!(mesh.triangles[1].uv, Some([0, 2, 3]));
+    assert!(Mesh::parse_obj("v 0 0 0\nf 1 2 3\n").is_err());
+    assert!(Mesh::parse_obj("v 0 0 0\n").is_err());
+}
+
+#[test]
+fn test_mesh_raycast() {
+    let mesh = Mesh::parse_obj("v -1 -1 0\nv 1 -1 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+    let vi = Vec3::new(0., 0., 5.);
+    let eye = Vec3::new(0., 0., -1.);
+    assert_eq!(mesh.raycast(&vi, &eye, 100., true, true).0, 5.);
+    let (_, _, bary) = mesh.raycast(&Vec3::new(0.5, -0.5, 5.), &eye, 100., true, true);
+    assert!((bary[0] - 0.125).abs() < 1e-6 && (bary[1] - 0.625).abs() < 1e-6);
+    // Counter-clockwise seen from +z, so the ray from +z hits the front face
+    assert_eq!(mesh.raycast(&vi, &eye, 100., false, true).0, 100.);
+    assert_eq!(mesh.raycast(&vi, &eye, 3., true, true).0, 3.);
+
+    let (idx, bary, dist) = mesh.closest(&Vec3::new(0., 0., 2.));
+    assert_eq!(idx, 0);
+    assert!((dist - 2.).abs() < 1e-6);
+    let n = mesh.normal(idx, &bary);
+    assert!((n.z - 1.).abs() < 1e-6);
+    assert!((mesh.signed_distance(&Vec3::new(0., 0., -2.)) + 2.).abs() < 1e-6);
+}
+
+#[test]
+fn test_signed_distance() {
+    // A regular tetrahedron, whose faces meet at edges sharper than a right angle
+    let mesh = Mesh::parse_obj(
+        "v 1 1 1
+v 1 -1 -1
+v -1 1 -1
+v -1 -1 1
+f 1 2 3
+f 1 4 2
+f 1 3 4
+f 2 4 3
+",
+    )
+    .unwrap();
+    assert!(mesh.signed_distance(&Vec3::zero()) < 0.);
+    assert!(0. < mesh.signed_distance(&Vec3::new(3., 3., 3.)));
+
+    // Points just outside the edge between the first two faces, leaning toward either face, are
+    // outside even though they are behind the plane of the other face
+    let edge = Vec3::new(1., 0., 0.);
+    let (n0, n1) = (mesh.face_normal(0), mesh.face_normal(1));
+    for (a, b) in [(n0, n1), (n1, n0)] {
+        let p = edge + (a * 0.9 + b * 0.1) * 0.01;
+        assert!(0. < mesh.signed_distance(&p), "{:?}", p);
+    }
+    assert!(mesh.signed_distance(&(edge - (n0 + n1) * 0.01)) < 0.);
+
+    // Likewise around a corner
+    let corner = Vec3::new(1., 1., 1.);
+    let p = corner + (mesh.face_normal(0) * 0.9 + mesh.face_normal(2) * 0.1) * 0.01;
+    assert!(0. < mesh.signed_distance(&p));
+    assert!(mesh.signed_distance(&(corner * 0.99)) < 0.);
+}
@@ -0,0 +1,159 @@
+//! Cook-Torrance microfacet reflection with the GGX (Trowbridge-Reitz) distribution.
+//!
+//! `alpha` is the width of the distribution, which is the square of the perceptual roughness
+//! given in materials. Directions all point away from the surface.
+use crate::render::RenderColor;
+use crate::sampling::tangent_frame;
+use crate::vec3::Vec3;
+use std::f32::consts::PI;
+
+/// Narrowest distribution evaluated, as a perfectly smooth surface has an infinitely sharp
+/// highlight that point lights can't be seen in.
+const MIN_ALPHA: f32 = 1e-3;
+
+/// Density of microfacet normals at cosine `n_dot_h` from the surface normal.
+pub fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha.max(MIN_ALPHA).powi(2);
+    let d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+/// Share of microfacets visible from both directions at the given cosines, by the separable
+/// Smith approximation.
+pub fn smith_masking(n_dot_v: f32, n_dot_l: f32, alpha: f32) -> f32 {
+    let a2 = alpha.max(MIN_ALPHA).powi(2);
+    let g1 = |c: f32| 2. * c / (c + (a2 + (1. - a2) * c * c).sqrt());
+    g1(n_dot_v) * g1(n_dot_l)
+}
+
+/// Schlick's approximation of the Fresnel reflectance at cosine `cos` from the normal, where
+/// `f0` is the reflectance at normal incidence.
+pub fn fresnel_schlick(f0: RenderColor, cos: f32) -> RenderColor {
+    let k = (1. - cos.clamp(0., 1.)).powi(5);
+    RenderColor::new(
+        f0.r + (1. - f0.r) * k,
+        f0.g + (1. - f0.g) * k,
+        f0.b + (1. - f0.b) * k,
+    )
+}
+
+/// Reflectance at normal incidence of a dielectric with refraction index `ior`.
+pub fn dielectric_f0(ior: f32) -> f32 {
+    ((ior - 1.) / (ior + 1.)).powi(2)
+}
+
+/// Light reflected toward `v` from light arriving from `l` on a surface with normal `n`, which
+/// is the specular BRDF times the cosine of `l`.
+pub fn ggx_specular(n: &Vec3, v: &Vec3, l: &Vec3, f0: RenderColor, alpha: f32) -> RenderColor {
+    let n_dot_l = n.dot(l);
+    let n_dot_v = n.dot(v).max(1e-4);
+    if n_dot_l <= 0. {
+        return RenderColor::zero();
+    }
+    let h = (*v + *l).normalized();
+    let d = ggx_distribution(n.dot(&h).max(0.), alpha);
+    let g = smith_masking(n_dot_v, n_dot_l, alpha);
+    fresnel_schlick(f0, v.dot(&h)) * (d * g / (4. * n_dot_v))
+}
+
+/// Maps a pair of uniform numbers in [0, 1) to a microfacet normal around `n`, distributed by
+/// the density of the normals times their cosine from `n`. Returns `n` itself for a smooth
+/// surface.
+pub fn sample_ggx(n: &Vec3, alpha: f32, u: f32, v: f32) -> Vec3 {
+    let tan2 = alpha * alpha * u / (1. - u);
+    let cos_theta = 1. / (1. + tan2).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * v;
+    let (tangent, bitangent) = tangent_frame(n);
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + *n * cos_theta)
+        .normalized()
+}
+
+/// Weight of a reflection toward `l` about the microfacet normal `h` drawn by `sample_ggx`,
+/// which is the specular BRDF times the cosine of `l` divided by the density of `l`.
+pub fn sample_ggx_weight(
+    n: &Vec3,
+    v: &Vec3,
+    l: &Vec3,
+    h: &Vec3,
+    f0: RenderColor,
+    alpha: f32,
+) -> RenderColor {
+    let (n_dot_v, n_dot_l, n_dot_h) = (n.dot(v), n.dot(l), n.dot(h));
+    if n_dot_v <= 0. || n_dot_l <= 0. || n_dot_h <= 0. {
+        return RenderColor::zero();
+    }
+    let v_dot_h = v.dot(h);
+    fresnel_schlick(f0, v_dot_h)
+        * (smith_masking(n_dot_v, n_dot_l, alpha) * v_dot_h / (n_dot_v * n_dot_h))
+}
+
+#[test]
+fn test_ggx_distribution() {
+    // The projected area of the microfacets adds up to the area of the surface
+    for alpha in [0.1, 0.5, 1.] {
+        let steps = 10000;
+        let sum: f32 = (0..steps)
+            .map(|i| {
+                let cos = (i as f32 + 0.5) / steps as f32;
+                ggx_distribution(cos, alpha) * cos * 2. * PI / steps as f32
+            })
+            .sum();
+        assert!((sum - 1.).abs() < 1e-2, "{}: {}", alpha, sum);
+    }
+    assert!(ggx_distribution(0.9, 0.1) < ggx_distribution(1., 0.1));
+}
+
+#[test]
+fn test_fresnel_schlick() {
+    let f0 = RenderColor::new(0.04, 0.5, 1.);
+    let normal = fresnel_schlick(f0, 1.);
+    assert_eq!((normal.r, normal.g, normal.b), (0.04, 0.5, 1.));
+    let grazing = fresnel_schlick(f0, 0.);
+    assert_eq!((grazing.r, grazing.g, grazing.b), (1., 1., 1.));
+    assert!((dielectric_f0(1.5) - 0.04).abs() < 1e-6);
+}
+
+#[test]
+fn test_sample_ggx() {
+    let n = Vec3::new(0., 1., 0.);
+    let h = sample_ggx(&n, 0., 0.7, 0.3);
+    assert!((h.y - 1.).abs() < 1e-6);
+
+    // Reflections sampled by the distribution estimate the same reflectance as evaluating it
+    // over a uniform grid of directions
+    let v = Vec3::new(0.6, 0.8, 0.);
+    let f0 = RenderColor::new(1., 1., 1.);
+    let alpha = 0.5;
+    let steps = 200;
+    let mut sampled = 0.;
+    for i in 0..steps {
+        for j in 0..steps {
+            let u = (i as f32 + 0.5) / steps as f32;
+            let w = (j as f32 + 0.5) / steps as f32;
+            let h = sample_ggx(&n, alpha, u, w);
+            let l = h * (2. * v.dot(&h)) - v;
+            sampled += sample_ggx_weight(&n, &v, &l, &h, f0, alpha).r;
+        }
+    }
+    sampled /= (steps * steps) as f32;
+    let mut evaluated = 0.;
+    for i in 0..steps {
+        for j in 0..steps {
+            // Uniform over the hemisphere, whose density is 1 / 2pi
+            let cos = (i as f32 + 0.5) / steps as f32;
+            let sin = (1. - cos * cos).sqrt();
+            let phi = 2. * PI * (j as f32 + 0.5) / steps as f32;
+            let l = Vec3::new(sin * phi.cos(), cos, sin * phi.sin());
+            evaluated += ggx_specular(&n, &v, &l, f0, alpha).r * 2. * PI;
+        }
+    }
+    evaluated /= (steps * steps) as f32;
+    assert!(0.5 < sampled && sampled <= 1.);
+    assert!(
+        (sampled - evaluated).abs() < 0.02,
+        "{} {}",
+        sampled,
+        evaluated
+    );
+}
@@ -0,0 +1,154 @@
+//! Real roots of low degree polynomials, used for analytic ray intersections.
+//!
+//! Computations are done in f64 because the quartic of a ray-torus intersection loses too
+//! much precision in f32.
+
+/// Real roots of a x^2 + b x + c = 0 in ascending order.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0. {
+        return if b == 0. { vec![] } else { vec![-c / b] };
+    }
+    let d = b * b - 4. * a * c;
+    if d < 0. {
+        return vec![];
+    }
+    // Avoid cancellation by computing the root with the larger magnitude first
+    let q = -0.5 * (b + b.signum() * d.sqrt());
+    let (x0, x1) = if q == 0. { (0., 0.) } else { (q / a, c / q) };
+    if x0 < x1 {
+        vec![x0, x1]
+    } else {
+        vec![x1, x0]
+    }
+}
+
+/// Real roots of x^3 + a x^2 + b x + c = 0 in ascending order.
+pub fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Substitute x = t - a / 3 to get the depressed cubic t^3 + p t + q = 0
+    let shift = a / 3.;
+    let p = b - a * shift;
+    let q = c + shift * (2. * shift * shift - b);
+    let d = (q / 2.).powi(2) + (p / 3.).powi(3);
+    let mut roots = if d > 0. {
+        // One real root, Cardano's formula
+        let u = (-q / 2. + d.sqrt()).cbrt();
+        let v = (-q / 2. - d.sqrt()).cbrt();
+        vec![u + v - shift]
+    } else if p == 0. {
+        vec![-shift]
+    } else {
+        // Three real roots, trigonometric method
+        let m = 2. * (-p / 3.).sqrt();
+        let theta = (3. * q / (p * m)).clamp(-1., 1.).acos() / 3.;
+        (0..3)
+            .map(|k| m * (theta - 2. * std::f64::consts::PI * k as f64 / 3.).cos() - shift)
+            .collect()
+    };
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    roots
+}
+
+/// Real roots of a x^4 + b x^3 + c x^2 + d x + e = 0 in ascending order, by Ferrari's method.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0. {
+        let mut roots = solve_cubic_general(b, c, d, e);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        return roots;
+    }
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+
+    // Substitute x = y - b / 4 to get the depressed quartic y^4 + p y^2 + q y + r = 0
+    let shift = b / 4.;
+    let p = c - 6. * shift * shift;
+    let q = d - 2. * c * shift + 8. * shift.powi(3);
+    let r = e - d * shift + c * shift * shift - 3. * shift.powi(4);
+
+    let mut roots = vec![];
+    if q.abs() < 1e-12 {
+        // Biquadratic in y^2
+        for y2 in solve_quadratic(1., p, r) {
+            if y2 >= 0. {
+                roots.push(y2.sqrt());
+                roots.push(-y2.sqrt());
+            }
+        }
+    } else {
+        // The resolvent cubic always has a positive root when q is not zero, which factors the
+        // quartic into two quadratics.
+        let z = solve_cubic(2. * p, p * p - 4. * r, -q * q)
+            .into_iter()
+            .fold(0., f64::max);
+        if z <= 0. {
+            return vec![];
+        }
+        let s = z.sqrt();
+        let m = (p + z) / 2.;
+        let n = q / (2. * s);
+        roots.extend(solve_quadratic(1., s, m - n));
+        roots.extend(solve_quadratic(1., -s, m + n));
+    }
+
+    let f = |x: f64| (((x + b) * x + c) * x + d) * x + e;
+    let df = |x: f64| ((4. * x + 3. * b) * x + 2. * c) * x + d;
+    let mut roots: Vec<_> = roots
+        .into_iter()
+        .map(|y| {
+            // Polish with Newton's method, which recovers the precision lost in the factorization
+            let mut x = y - shift;
+            for _ in 0..2 {
+                let slope = df(x);
+                if slope != 0. {
+                    x -= f(x) / slope;
+                }
+            }
+            x
+        })
+        .collect();
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    roots
+}
+
+fn solve_cubic_general(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a == 0. {
+        solve_quadratic(b, c, d)
+    } else {
+        solve_cubic(b / a, c / a, d / a)
+    }
+}
+
+#[test]
+fn test_solve_quadratic() {
+    assert_eq!(solve_quadratic(1., -3., 2.), vec![1., 2.]);
+    assert_eq!(solve_quadratic(1., 0., 1.), Vec::<f64>::new());
+    assert_eq!(solve_quadratic(0., 2., -4.), vec![2.]);
+}
+
+#[test]
+fn test_solve_cubic() {
+    // (x - 1)(x - 2)(x - 3)
+    let roots = solve_cubic(-6., 11., -6.);
+    assert_eq!(roots.len(), 3);
+    for (x, expected) in roots.iter().zip([1., 2., 3.]) {
+        assert!((x - expected).abs() < 1e-9);
+    }
+    // x^3 + x + 2 = (x + 1)(x^2 - x + 2)
+    let roots = solve_cubic(0., 1., 2.);
+    assert_eq!(roots.len(), 1);
+    assert!((roots[0] + 1.).abs() < 1e-9);
+}
+
+#[test]
+fn test_solve_quartic() {
+    // (x + 2)(x - 1)(x - 3)(x - 4)
+    let roots = solve_quartic(2., -12., 6., 52., -48.);
+    assert_eq!(roots.len(), 4);
+    for (x, expected) in roots.iter().zip([-2., 1., 3., 4.]) {
+        assert!((x - expected).abs() < 1e-9);
+    }
+    // (x^2 - 1)(x^2 + 1) has only two real roots
+    let roots = solve_quartic(1., 0., 0., 0., -1.);
+    assert_eq!(roots.len(), 2);
+    assert!((roots[0] + 1.).abs() < 1e-9 && (roots[1] - 1.).abs() < 1e-9);
+    // (x^2 + 1)(x^2 + 2) has none
+    assert!(solve_quartic(1., 0., 3., 0., 2.).is_empty());
+}
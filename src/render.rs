@@ -1,21 +1,39 @@
+use crate::background::{Background, BackgroundSerial};
+use crate::bvh::{Aabb, Bvh};
+use crate::framebuffer::Framebuffer;
+use crate::light::RenderLight;
+use crate::mesh::Mesh;
+use crate::microfacet::{
+    dielectric_f0, fresnel_schlick, ggx_specular, sample_ggx, sample_ggx_weight,
+};
 use crate::modutil::*;
 use crate::pixelutil::*;
+use crate::polynomial::{solve_quadratic, solve_quartic};
 use crate::quat::Quat;
+use crate::sampling::{sample_cosine_hemisphere, sample_disk, stratified, AntiAliasing, Rng};
+use crate::sdf::{gradient_normal, smooth_min, SdfShape};
+use crate::tonemap::ToneMapping;
+use crate::transform::Transform;
 use crate::vec3::Vec3;
 use image::DynamicImage;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
+use std::ops::{Add, AddAssign, Mul};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::Arc;
 
 pub const MAX_REFLECTIONS: i32 = 3;
 pub const MAX_REFRACTIONS: i32 = 10;
+pub const GLOSSY_SAMPLES: u32 = 8;
+pub const DEFAULT_FOCUS_DISTANCE: f32 = 300.;
 
 const OUTONLY: u32 = 1;
 const INONLY: u32 = 1 << 1;
 const RIGNORE: u32 = 1 << 2;
 const GIGNORE: u32 = 1 << 3;
 const BIGNORE: u32 = 1 << 4;
+const GLOSSY: u32 = 1 << 5;
 // const RONLY: u32 = (GIGNORE|BIGNORE);
 // const GONLY: u32 = (RIGNORE|BIGNORE);
 // const BONLY: u32 = (RIGNORE|GIGNORE);
@@ -41,6 +59,39 @@ impl RenderColor {
     }
 }
 
+impl Add for RenderColor {
+    type Output = RenderColor;
+
+    fn add(self, o: Self) -> RenderColor {
+        RenderColor::new(self.r + o.r, self.g + o.g, self.b + o.b)
+    }
+}
+
+impl AddAssign for RenderColor {
+    fn add_assign(&mut self, o: Self) {
+        self.r += o.r;
+        self.g += o.g;
+        self.b += o.b;
+    }
+}
+
+impl Mul<f32> for RenderColor {
+    type Output = RenderColor;
+
+    fn mul(self, o: f32) -> RenderColor {
+        RenderColor::new(self.r * o, self.g * o, self.b * o)
+    }
+}
+
+/// Multiplies each channel, like light of one color reflected by a surface of another.
+impl Mul for RenderColor {
+    type Output = RenderColor;
+
+    fn mul(self, o: Self) -> RenderColor {
+        RenderColor::new(self.r * o.r, self.g * o.g, self.b * o.b)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum RenderPattern {
     Solid,
@@ -48,8 +99,9 @@ pub enum RenderPattern {
     RepeatedGradation,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
 pub enum UVMap {
+    #[default]
     XY,
     YZ,
     ZX,
@@ -62,6 +114,50 @@ pub enum TextureFilter {
     Bilinear,
 }
 
+/// Physically based description of a surface, lit by the GGX microfacet model instead of the
+/// Phong parameters of the material it belongs to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PbrMaterial {
+    /// Albedo of a dielectric, or the reflectance of a metal
+    pub base_color: RenderColor,
+    /// Blend from dielectric at 0 to metal at 1
+    pub metallic: f32,
+    /// Perceptual roughness from 0 (mirror) to 1
+    pub roughness: f32,
+    /// Refraction index deciding the reflectance of the dielectric part. Transparent
+    /// materials still refract by `n`.
+    pub ior: f32,
+}
+
+impl Default for PbrMaterial {
+    fn default() -> Self {
+        Self {
+            base_color: RenderColor::new(0.8, 0.8, 0.8),
+            metallic: 0.,
+            roughness: 0.5,
+            ior: 1.5,
+        }
+    }
+}
+
+impl PbrMaterial {
+    /// Width of the GGX distribution
+    pub fn alpha(&self) -> f32 {
+        self.roughness * self.roughness
+    }
+
+    /// Reflectance at normal incidence of a surface whose albedo is `base`.
+    pub fn f0(&self, base: RenderColor) -> RenderColor {
+        let f = dielectric_f0(self.ior) * (1. - self.metallic);
+        RenderColor::new(
+            f + base.r * self.metallic,
+            f + base.g * self.metallic,
+            f + base.b * self.metallic,
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RenderMaterialSerial {
     name: String,
@@ -72,6 +168,14 @@ pub struct RenderMaterialSerial {
     n: f32,                /* refraction constant */
     glow_dist: f32,
     frac: RenderColor, /* refraction per spectrum */
+    #[serde(default = "RenderColor::zero")]
+    absorption: RenderColor, /* absorption per unit length inside */
+    #[serde(default = "RenderColor::zero")]
+    emission: RenderColor, /* light emitted by the surface */
+    #[serde(default)]
+    roughness: f32, /* spread of reflections */
+    #[serde(default)]
+    pbr: Option<PbrMaterial>,
     pattern: RenderPattern,
     pattern_scale: f32,
     pattern_angle_scale: f32,
@@ -87,7 +191,11 @@ pub struct RenderMaterial {
     t: f32,                /* transparency, unit length per decay */
     n: f32,                /* refraction constant */
     glow_dist: f32,
-    frac: RenderColor, /* refraction per spectrum */
+    frac: RenderColor,       /* refraction per spectrum */
+    absorption: RenderColor, /* absorption per unit length inside */
+    emission: RenderColor,   /* light emitted by the surface */
+    roughness: f32,          /* spread of reflections */
+    pbr: Option<PbrMaterial>,
     pattern: RenderPattern,
     pattern_scale: f32,
     pattern_angle_scale: f32,
@@ -121,6 +229,10 @@ impl RenderMaterial {
             n,
             glow_dist: 0.,
             frac: RenderColor::new(1., 1., 1.),
+            absorption: RenderColor::zero(),
+            emission: RenderColor::zero(),
+            roughness: 0.,
+            pbr: None,
             pattern: RenderPattern::Solid,
             pattern_scale: 1.,
             pattern_angle_scale: 1.,
@@ -145,6 +257,38 @@ impl RenderMaterial {
         self
     }
 
+    /// Light travelling inside the object decays by exp(-absorption * distance) per channel,
+    /// so the object gets tinted with the complementary color as it gets thicker.
+    #[allow(dead_code)]
+    pub fn absorption(mut self, absorption: RenderColor) -> Self {
+        self.absorption = absorption;
+        self
+    }
+
+    /// Light given off by the surface regardless of lighting. Only the path tracer lets it
+    /// illuminate other objects; the other modes just show the surface that bright.
+    #[allow(dead_code)]
+    pub fn emission(mut self, emission: RenderColor) -> Self {
+        self.emission = emission;
+        self
+    }
+
+    /// Spreads the specular reflection around the mirror direction, from a perfect mirror at 0
+    /// to very blurry at 1, like the roughness of a physically based material.
+    #[allow(dead_code)]
+    pub fn roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    /// Lights the surface by the physically based model instead of the Phong parameters. The
+    /// base color takes the place of the diffuse color in patterns.
+    #[allow(dead_code)]
+    pub fn pbr(mut self, pbr: PbrMaterial) -> Self {
+        self.pbr = Some(pbr);
+        self
+    }
+
     pub fn pattern(mut self, pattern: RenderPattern) -> Self {
         self.pattern = pattern;
         self
@@ -190,6 +334,10 @@ impl RenderMaterial {
             n: self.n,
             glow_dist: self.glow_dist,
             frac: self.frac,
+            absorption: self.absorption,
+            emission: self.emission,
+            roughness: self.roughness,
+            pbr: self.pbr,
             pattern: self.pattern,
             pattern_scale: self.pattern_scale,
             pattern_angle_scale: self.pattern_angle_scale,
@@ -208,6 +356,10 @@ impl RenderMaterial {
             n: obj.n,
             glow_dist: obj.glow_dist,
             frac: obj.frac,
+            absorption: obj.absorption,
+            emission: obj.emission,
+            roughness: obj.roughness,
+            pbr: obj.pbr,
             pattern: obj.pattern,
             pattern_scale: obj.pattern_scale,
             pattern_angle_scale: obj.pattern_angle_scale,
@@ -217,6 +369,12 @@ impl RenderMaterial {
         })
     }
 
+    /// Width of the GGX distribution that reflections are spread over, or 0 for a mirror.
+    fn reflection_alpha(&self) -> f32 {
+        self.pbr
+            .map_or(self.roughness * self.roughness, |pbr| pbr.alpha())
+    }
+
     fn get_uv(&self, pos: &Vec3, uvmap: UVMap) -> (f32, f32) {
         match uvmap {
             UVMap::XY => (pos.x / self.pattern_scale, pos.y / self.pattern_scale),
@@ -296,22 +454,21 @@ impl RenderMaterialInterface for RenderMaterial {
                 }
             }
         }
+        let diffuse = self.pbr.map_or(self.diffuse, |pbr| pbr.base_color);
         match self.pattern {
-            RenderPattern::Solid => self.diffuse,
+            RenderPattern::Solid => diffuse,
             RenderPattern::Checkerboard => {
                 let ix = u.floor() as i32;
                 let iy = v.floor() as i32;
                 if (ix + iy) % 2 == 0 {
                     RenderColor::new(0., 0., 0.)
                 } else {
-                    self.diffuse
+                    diffuse
                 }
             }
-            RenderPattern::RepeatedGradation => RenderColor::new(
-                self.diffuse.r * fmod(u, 1.),
-                self.diffuse.g * fmod(v, 1.),
-                self.diffuse.b,
-            ),
+            RenderPattern::RepeatedGradation => {
+                RenderColor::new(diffuse.r * fmod(u, 1.), diffuse.g * fmod(v, 1.), diffuse.b)
+            }
         }
     }
 }
@@ -332,10 +489,38 @@ pub struct RenderFloorSerial {
     uvmap: UVMap,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderMeshSerial {
+    material: String,
+    path: String, /* Wavefront OBJ file */
+    #[serde(default)]
+    transform: Transform,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderBoxSerial {
+    material: String,
+    center: Vec3,
+    half_extents: Vec3,
+    #[serde(default)]
+    rotation: Option<Quat>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum RenderObjectSerial {
     Sphere(RenderSphereSerial),
     Floor(RenderFloorSerial),
+    Mesh(RenderMeshSerial),
+    Box(RenderBoxSerial),
+    Cylinder(RenderCylinderSerial),
+    Cone(RenderConeSerial),
+    Disk(RenderDiskSerial),
+    Torus(RenderTorusSerial),
+    Csg(RenderCsgSerial),
+    Transformed(RenderTransformedSerial),
+    Instance(RenderInstanceSerial),
+    Sdf(RenderSdfSerial),
+    SmoothUnion(RenderSmoothUnionSerial),
 }
 
 pub struct DeserializeError {
@@ -343,7 +528,7 @@ pub struct DeserializeError {
 }
 
 impl DeserializeError {
-    fn new(s: &str) -> Self {
+    pub(crate) fn new(s: &str) -> Self {
         DeserializeError { s: s.to_string() }
     }
 }
@@ -365,13 +550,57 @@ impl From<serde_yaml::Error> for DeserializeError {
     }
 }
 
+/// A triangle of a mesh hit by a ray, with the barycentric weights of the hit point, which
+/// decide the normal and texture coordinates there.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshHit {
+    pub idx: usize,
+    pub bary: [f32; 3],
+}
+
 pub trait RenderObjectInterface {
     fn get_material(&self) -> &RenderMaterial;
+    /// Material of the surface at `position`, which only differs from `get_material` for
+    /// objects made of several others.
+    fn get_material_at(&self, _position: &Vec3) -> &RenderMaterial {
+        self.get_material()
+    }
     fn get_diffuse(&self, position: &Vec3) -> RenderColor;
     fn get_specular(&self, position: &Vec3) -> RenderColor;
-    fn get_normal(&self, position: &Vec3) -> Vec3;
+    /// Outward surface normal. Defaults to the gradient of `distance`, estimated by central
+    /// differences, for objects without an analytic one.
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        gradient_normal(position, |p| self.distance(p))
+    }
     fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32;
+    /// Like `raycast`, and also returns the triangle hit if the object is a triangle mesh.
+    fn raycast_hit(
+        &self,
+        vi: &Vec3,
+        eye: &Vec3,
+        ray_length: f32,
+        flags: u32,
+    ) -> (f32, Option<MeshHit>) {
+        (self.raycast(vi, eye, ray_length, flags), None)
+    }
+    /// Normal at a point found by `raycast_hit`, on the triangle `hit` if there was one.
+    fn get_hit_normal(&self, position: &Vec3, _hit: Option<MeshHit>) -> Vec3 {
+        self.get_normal(position)
+    }
+    /// Diffuse color at a point found by `raycast_hit`, on the triangle `hit` if there was one.
+    fn get_hit_diffuse(&self, position: &Vec3, _hit: Option<MeshHit>) -> RenderColor {
+        self.get_diffuse(position)
+    }
+    /// False for objects that only have a distance function, so `raycast` never hits them and
+    /// they can only be rendered by ray marching.
+    fn supports_raycast(&self) -> bool {
+        true
+    }
+    /// Signed distance to the surface, negative inside. Objects without an inside, like disks,
+    /// are never negative.
     fn distance(&self, vi: &Vec3) -> f32;
+    /// Returns None if the object is unbounded.
+    fn bounding_box(&self) -> Option<Aabb>;
     fn serialize(&self) -> RenderObjectSerial;
 }
 
@@ -471,7 +700,12 @@ impl RenderObjectInterface for RenderSphere {
     }
 
     fn distance(&self, vi: &Vec3) -> f32 {
-        ((self.org - *vi).len() - self.r).max(0.)
+        (self.org - *vi).len() - self.r
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.r, self.r, self.r);
+        Some(Aabb::new(self.org - r, self.org + r))
     }
 
     fn serialize(&self) -> RenderObjectSerial {
@@ -569,7 +803,11 @@ impl RenderObjectInterface for RenderFloor {
     }
 
     fn distance(&self, vi: &Vec3) -> f32 {
-        (vi - &self.org).dot(&self.face_normal).max(0.)
+        (vi - &self.org).dot(&self.face_normal)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
     }
 
     fn serialize(&self) -> RenderObjectSerial {
@@ -583,182 +821,2159 @@ impl RenderObjectInterface for RenderFloor {
 }
 
 #[derive(Clone)]
-pub enum RenderObject {
-    Sphere(RenderSphere),
-    Floor(RenderFloor),
+pub struct RenderMesh {
+    material: Arc<RenderMaterial>,
+    path: String,
+    transform: Transform,
+    // Vertices are stored with the transform already applied. Shared because meshes can be big.
+    mesh: Arc<Mesh>,
+    uvmap: UVMap, /* Used only if the mesh has no texture coordinates */
 }
 
-impl RenderObject {
-    pub fn get_interface(&self) -> &dyn RenderObjectInterface {
-        match self {
-            RenderObject::Sphere(ref obj) => obj as &dyn RenderObjectInterface,
-            RenderObject::Floor(ref obj) => obj as &dyn RenderObjectInterface,
-        }
+impl RenderMesh {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        material: Arc<RenderMaterial>,
+        path: &str,
+        transform: Transform,
+    ) -> Result<RenderObject, io::Error> {
+        Ok(RenderObject::Mesh(RenderMesh::new_raw(
+            material, path, transform,
+        )?))
     }
-}
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
-struct CameraSerial {
-    position: Vec3,
-    pyr: Vec3,
-}
+    /// Error when loading the OBJ file failed
+    pub fn new_raw(
+        material: Arc<RenderMaterial>,
+        path: &str,
+        transform: Transform,
+    ) -> Result<RenderMesh, io::Error> {
+        Self::load(material, path, Path::new(path), transform)
+    }
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
-struct CameraKeyframeSerial {
-    camera: CameraSerial,
-    velocity: Vec3,
-    camera_target: Option<Vec3>,
-    duration: f32,
-}
+    /// Loads the OBJ file at `file`, which `path` as written in the scene refers to.
+    fn load(
+        material: Arc<RenderMaterial>,
+        path: &str,
+        file: &Path,
+        transform: Transform,
+    ) -> Result<RenderMesh, io::Error> {
+        Ok(RenderMesh {
+            material,
+            path: path.to_string(),
+            transform,
+            mesh: Arc::new(Mesh::load_obj(file)?.transformed(&transform)),
+            uvmap: UVMap::XY,
+        })
+    }
 
-#[derive(Clone, Serialize, Deserialize)]
-struct CameraMotionSerial(Vec<CameraKeyframeSerial>);
+    #[allow(dead_code)]
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
 
-#[derive(Clone, Copy)]
-pub struct Camera {
-    pub position: Vec3,
-    pub pyr: Vec3,
-    pub rotation: Quat,
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderMeshSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Mesh(
+            Self::load(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderMesh couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                &serial.path,
+                &ren.scene_dir.join(&serial.path),
+                serial.transform,
+            )
+            .map_err(|e| {
+                DeserializeError::new(&format!("RenderMesh couldn't load {}: {}", serial.path, e))
+            })?,
+        ))
+    }
 }
 
-impl From<CameraSerial> for Camera {
-    fn from(o: CameraSerial) -> Camera {
-        Camera {
-            position: o.position,
-            pyr: o.pyr,
-            rotation: Quat::from_pyr(&o.pyr),
-        }
+impl RenderMesh {
+    /// Triangle and barycentric weights of `hit`, or of the point nearest to `position` on
+    /// the mesh for points not found by a ray, such as those found by ray marching.
+    fn locate(&self, position: &Vec3, hit: Option<MeshHit>) -> (usize, [f32; 3]) {
+        hit.map_or_else(
+            || {
+                let (idx, bary, _) = self.mesh.closest(position);
+                (idx, bary)
+            },
+            |hit| (hit.idx, hit.bary),
+        )
     }
 }
 
-#[derive(Clone)]
-pub struct CameraKeyframe {
-    pub camera: Camera,
-    pub velocity: Vec3,
-    camera_target: Option<Vec3>,
-    pub duration: f32,
-}
+impl RenderObjectInterface for RenderMesh {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
 
-#[derive(Clone)]
-pub struct CameraMotion(pub Vec<CameraKeyframe>);
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.get_hit_diffuse(position, None)
+    }
 
-#[derive(Clone)]
-pub struct RenderEnv {
-    pub camera: Camera, /* camera position */
-    pub camera_motion: CameraMotion,
-    pub xres: i32,
-    pub yres: i32,
-    pub xfov: f32,
-    pub yfov: f32,
-    // Materials are stored in a string map, whose key is a string.
-    // A material is stored in Arc in order to share between global material list
-    // and each object. I'm not sure if it's better than embedding into each object.
-    // We wanted to but cannot use reference (borrow checker gets mad about enums)
-    // nor Rc (multithreading gets mad).
-    pub materials: HashMap<String, Arc<RenderMaterial>>,
-    pub objects: Vec<RenderObject>,
-    pub light: Vec3,
-    pub bgproc: fn(ren: &RenderEnv, pos: &Vec3) -> RenderColor,
-    pub use_raymarching: bool,
-    glow_effect: Option<f32>,
-    pub max_reflections: i32,
-    pub max_refractions: i32,
+    fn get_hit_diffuse(&self, position: &Vec3, hit: Option<MeshHit>) -> RenderColor {
+        let (idx, bary) = self.locate(position, hit);
+        let uv = self.mesh.uv(idx, &bary).unwrap_or_else(|| {
+            self.material
+                .get_uv(&(position - &self.transform.translation), self.uvmap)
+        });
+        self.material.lookup_texture(uv)
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        self.get_hit_normal(position, None)
+    }
+
+    fn get_hit_normal(&self, position: &Vec3, hit: Option<MeshHit>) -> Vec3 {
+        let (idx, bary) = self.locate(position, hit);
+        self.mesh.normal(idx, &bary)
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        self.raycast_hit(vi, eye, ray_length, flags).0
+    }
+
+    fn raycast_hit(
+        &self,
+        vi: &Vec3,
+        eye: &Vec3,
+        ray_length: f32,
+        flags: u32,
+    ) -> (f32, Option<MeshHit>) {
+        // Front faces are where the ray enters the object, back faces where it exits.
+        let (t, idx, bary) = self.mesh.raycast(
+            vi,
+            eye,
+            ray_length,
+            0 == (flags & INONLY),
+            0 == (flags & OUTONLY),
+        );
+        (t, Some(MeshHit { idx, bary }).filter(|_| t < ray_length))
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        self.mesh.signed_distance(vi)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.mesh.bounds())
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Mesh(RenderMeshSerial {
+            material: self.material.name.clone(),
+            path: self.path.clone(),
+            transform: self.transform,
+        })
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Scene {
-    camera: CameraSerial,
-    camera_motion: CameraMotionSerial,
-    max_reflections: i32,
-    max_refractions: i32,
-    materials: HashMap<String, RenderMaterialSerial>,
-    objects: Vec<RenderObjectSerial>,
+#[derive(Clone)]
+pub struct RenderBox {
+    material: Arc<RenderMaterial>,
+    center: Vec3,
+    half_extents: Vec3,
+    rotation: Option<Quat>, /* None for an axis aligned box */
 }
 
-impl RenderEnv {
-    pub fn new(
-        cam: Vec3, /* camera position */
-        pyr: Vec3, /* camera direction in pitch yaw roll form */
-        xres: i32,
-        yres: i32,
-        xfov: f32,
-        yfov: f32,
-        bgproc: fn(ren: &RenderEnv, pos: &Vec3) -> RenderColor,
-    ) -> Self {
-        RenderEnv {
-            camera: Camera {
-                position: cam,
-                pyr,
-                rotation: Quat::from_pyr(&pyr),
-            },
-            camera_motion: CameraMotion(vec![]),
-            xres,
-            yres,
-            xfov,
-            yfov,
-            materials: HashMap::new(),
-            objects: Vec::new(),
-            light: Vec3::new(0., 0., 1.),
-            bgproc,
-            use_raymarching: false,
-            glow_effect: None,
-            max_reflections: MAX_REFLECTIONS,
-            max_refractions: MAX_REFRACTIONS,
+impl RenderBox {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(material: Arc<RenderMaterial>, center: Vec3, half_extents: Vec3) -> RenderObject {
+        RenderObject::Box(RenderBox::new_raw(material, center, half_extents))
+    }
+
+    pub fn new_raw(material: Arc<RenderMaterial>, center: Vec3, half_extents: Vec3) -> RenderBox {
+        RenderBox {
+            material,
+            center,
+            half_extents,
+            rotation: None,
         }
     }
 
-    pub fn materials(mut self, materials: HashMap<String, Arc<RenderMaterial>>) -> Self {
-        self.materials = materials;
+    #[allow(dead_code)]
+    pub fn rotation(mut self, rotation: Quat) -> Self {
+        self.rotation = Some(rotation);
         self
     }
 
-    pub fn objects(mut self, objects: Vec<RenderObject>) -> Self {
-        self.objects = objects;
-        self
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderBoxSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        let mut ret = Self::new_raw(
+            ren.materials
+                .get(&serial.material)
+                .ok_or_else(|| {
+                    DeserializeError::new(&format!(
+                        "RenderBox couldn't find material {}",
+                        serial.material
+                    ))
+                })?
+                .clone(),
+            serial.center,
+            serial.half_extents,
+        );
+        ret.rotation = serial.rotation;
+        Ok(RenderObject::Box(ret))
     }
 
-    pub fn light(mut self, light: Vec3) -> Self {
-        self.light = light.normalized();
-        self
+    /// Rotate a world space vector into the box's frame.
+    fn to_local(&self, v: &Vec3) -> Vec3 {
+        match self.rotation {
+            Some(q) => q.conjugated().transform(v),
+            None => *v,
+        }
     }
 
-    pub fn use_raymarching(mut self, f: bool) -> Self {
-        self.use_raymarching = f;
-        self
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        match self.rotation {
+            Some(q) => q.transform(v),
+            None => *v,
+        }
     }
 
-    pub fn glow_effect(mut self, v: Option<f32>) -> Self {
-        self.glow_effect = v;
-        self
+    /// Index of the axis whose faces are nearest to the local point `p`.
+    fn face_axis(&self, p: &Vec3) -> usize {
+        let h = &self.half_extents;
+        let d = [p.x.abs() - h.x, p.y.abs() - h.y, p.z.abs() - h.z];
+        if d[0] >= d[1] && d[0] >= d[2] {
+            0
+        } else if d[1] >= d[2] {
+            1
+        } else {
+            2
+        }
     }
+}
 
-    pub fn serialize(&self) -> Result<String, std::io::Error> {
-        let mut sceneobj = Scene {
-            camera: CameraSerial {
-                position: self.camera.position,
-                pyr: self.camera.pyr,
+impl RenderObjectInterface for RenderBox {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        let p = self.to_local(&(position - &self.center));
+        let scale = self.material.pattern_scale;
+        // Each face is mapped by the two local coordinates along it
+        let uv = match self.face_axis(&p) {
+            0 => (p.z / scale, p.y / scale),
+            1 => (p.x / scale, p.z / scale),
+            _ => (p.x / scale, p.y / scale),
+        };
+        self.material.lookup_texture(uv)
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        let p = self.to_local(&(position - &self.center));
+        let n = match self.face_axis(&p) {
+            0 => Vec3::new(p.x.signum(), 0., 0.),
+            1 => Vec3::new(0., p.y.signum(), 0.),
+            _ => Vec3::new(0., 0., p.z.signum()),
+        };
+        self.to_world(&n)
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        // Slab test in the box's frame
+        let o = self.to_local(&(vi - &self.center));
+        let d = self.to_local(eye);
+        let h = &self.half_extents;
+        let mut t0 = -f32::INFINITY;
+        let mut t1 = f32::INFINITY;
+        for (o, d, h) in [(o.x, d.x, h.x), (o.y, d.y, h.y), (o.z, d.z, h.z)] {
+            let inv = 1. / d;
+            let (near, far) = ((-h - o) * inv, (h - o) * inv);
+            t0 = t0.max(near.min(far));
+            t1 = t1.min(near.max(far));
+        }
+        if t1 < t0 {
+            return ray_length;
+        }
+        select_hit(t0, t1, ray_length, flags)
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        let p = self.to_local(&(vi - &self.center));
+        let h = &self.half_extents;
+        let q = Vec3::new(p.x.abs() - h.x, p.y.abs() - h.y, p.z.abs() - h.z);
+        let outside = Vec3::new(q.x.max(0.), q.y.max(0.), q.z.max(0.)).len();
+        let inside = q.x.max(q.y).max(q.z).min(0.);
+        outside + inside
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let h = &self.half_extents;
+        let corners: Vec<_> = (0..8)
+            .map(|i| {
+                let corner = Vec3::new(
+                    if i & 1 == 0 { -h.x } else { h.x },
+                    if i & 2 == 0 { -h.y } else { h.y },
+                    if i & 4 == 0 { -h.z } else { h.z },
+                );
+                self.center + self.to_world(&corner)
+            })
+            .collect();
+        Some(Aabb::from_points(&corners))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Box(RenderBoxSerial {
+            material: self.material.name.clone(),
+            center: self.center,
+            half_extents: self.half_extents,
+            rotation: self.rotation,
+        })
+    }
+}
+
+/// Splits `v` into its length along the unit vector `axis` and the component perpendicular to it.
+fn axial(v: &Vec3, axis: &Vec3) -> (f32, Vec3) {
+    let h = v.dot(axis);
+    (h, *v - *axis * h)
+}
+
+/// Picks the entry hit `t0` or the exit hit `t1` of a convex object according to `flags`.
+fn select_hit(t0: f32, t1: f32, ray_length: f32, flags: u32) -> f32 {
+    if 0 == (flags & OUTONLY) && t0 >= 0. && t0 < ray_length {
+        t0
+    } else if 0 == (flags & INONLY) && 0. < t1 && t1 < ray_length {
+        t1
+    } else {
+        ray_length
+    }
+}
+
+/// Bounding box of a disk with radius `r` perpendicular to the unit vector `axis`.
+fn disk_bounds(center: &Vec3, axis: &Vec3, r: f32) -> Aabb {
+    let extent = |a: f32| r * (1. - a * a).max(0.).sqrt();
+    let e = Vec3::new(extent(axis.x), extent(axis.y), extent(axis.z));
+    Aabb::new(*center - e, *center + e)
+}
+
+/// Entry and exit parameters of the ray `w + t * d` through a capped cone frustum whose base
+/// is at the origin, extending `height` along `axis` with radii `r` at the base and `top_r` at
+/// the top. A cylinder is the special case of equal radii.
+fn frustum_interval(
+    w: &Vec3,
+    d: &Vec3,
+    axis: &Vec3,
+    height: f32,
+    r: f32,
+    top_r: f32,
+) -> Option<(f32, f32)> {
+    let (h0, w_perp) = axial(w, axis);
+    let (dn, d_perp) = axial(d, axis);
+
+    // Slab between the caps
+    let (mut t0, mut t1) = if dn == 0. {
+        if h0 < 0. || height < h0 {
+            return None;
+        }
+        (-f32::INFINITY, f32::INFINITY)
+    } else {
+        let (a, b) = (-h0 / dn, (height - h0) / dn);
+        (a.min(b), a.max(b))
+    };
+
+    // Inside of the infinite cone where a t^2 + b t + c <= 0
+    let k = (top_r - r) / height;
+    let rr0 = r + k * h0;
+    let a = d_perp.squared_len() - k * k * dn * dn;
+    let b = 2. * (d_perp.dot(&w_perp) - k * dn * rr0);
+    let c = w_perp.squared_len() - rr0 * rr0;
+    let roots: Vec<_> = solve_quadratic(a as f64, b as f64, c as f64)
+        .into_iter()
+        .map(|t| t as f32)
+        .collect();
+    match roots[..] {
+        [] => {
+            if 0. < c {
+                return None;
+            }
+        }
+        [t] => {
+            if 0. < b {
+                t1 = t1.min(t);
+            } else {
+                t0 = t0.max(t);
+            }
+        }
+        [x0, x1] => {
+            if 0. < a {
+                t0 = t0.max(x0);
+                t1 = t1.min(x1);
+            } else if t0 <= x0 {
+                // The ray crosses both nappes of the double cone; only one overlaps the slab.
+                t1 = t1.min(x0);
+            } else {
+                t0 = t0.max(x1);
+            }
+        }
+        _ => unreachable!(),
+    }
+    if t0 <= t1 {
+        Some((t0, t1))
+    } else {
+        None
+    }
+}
+
+/// Exact signed distance from `w` to the frustum described in `frustum_interval`.
+fn frustum_distance(w: &Vec3, axis: &Vec3, height: f32, r: f32, top_r: f32) -> f32 {
+    let (h, perp) = axial(w, axis);
+    let half = height / 2.;
+    // Work in the plane spanned by the axis and the point, centered between the caps
+    let (qx, qy) = (perp.len(), h - half);
+    let ca = (
+        qx - qx.min(if qy < 0. { r } else { top_r }),
+        qy.abs() - half,
+    );
+    let k2 = (top_r - r, height);
+    let t =
+        (((top_r - qx) * k2.0 + (half - qy) * k2.1) / (k2.0 * k2.0 + k2.1 * k2.1)).clamp(0., 1.);
+    let cb = (qx - top_r + k2.0 * t, qy - half + k2.1 * t);
+    let s = if cb.0 < 0. && ca.1 < 0. { -1. } else { 1. };
+    s * (ca.0 * ca.0 + ca.1 * ca.1)
+        .min(cb.0 * cb.0 + cb.1 * cb.1)
+        .sqrt()
+}
+
+fn frustum_normal(w: &Vec3, axis: &Vec3, height: f32, r: f32, top_r: f32) -> Vec3 {
+    let (h, perp) = axial(w, axis);
+    let k = (top_r - r) / height;
+    let side = (perp.len() - (r + k * h)) / (1. + k * k).sqrt();
+    if side >= -h && side >= h - height {
+        (perp.normalized() - *axis * k).normalized()
+    } else if -h > h - height {
+        *axis * -1.
+    } else {
+        *axis
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderCylinderSerial {
+    material: String,
+    org: Vec3, /* Center of the base */
+    axis: Vec3,
+    r: f32,
+    height: f32,
+    #[serde(default)]
+    uvmap: UVMap,
+}
+
+#[derive(Clone)]
+pub struct RenderCylinder {
+    material: Arc<RenderMaterial>,
+    org: Vec3,  /* Center of the base */
+    axis: Vec3, /* Unit vector from the base to the top */
+    r: f32,
+    height: f32,
+    uvmap: UVMap,
+}
+
+impl RenderCylinder {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        height: f32,
+    ) -> RenderObject {
+        RenderObject::Cylinder(RenderCylinder::new_raw(material, org, axis, r, height))
+    }
+
+    pub fn new_raw(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        height: f32,
+    ) -> RenderCylinder {
+        RenderCylinder {
+            material,
+            org,
+            axis: axis.normalized(),
+            r,
+            height,
+            uvmap: UVMap::XY,
+        }
+    }
+
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderCylinderSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Cylinder(
+            Self::new_raw(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderCylinder couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                serial.org,
+                serial.axis,
+                serial.r,
+                serial.height,
+            )
+            .uvmap(serial.uvmap),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderCylinder {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.material
+            .lookup_texture(self.material.get_uv(&(position - &self.org), self.uvmap))
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        frustum_normal(
+            &(position - &self.org),
+            &self.axis,
+            self.height,
+            self.r,
+            self.r,
+        )
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        match frustum_interval(
+            &(vi - &self.org),
+            eye,
+            &self.axis,
+            self.height,
+            self.r,
+            self.r,
+        ) {
+            Some((t0, t1)) => select_hit(t0, t1, ray_length, flags),
+            None => ray_length,
+        }
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        frustum_distance(&(vi - &self.org), &self.axis, self.height, self.r, self.r)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.org + self.axis * self.height;
+        Some(
+            disk_bounds(&self.org, &self.axis, self.r)
+                .union(&disk_bounds(&top, &self.axis, self.r)),
+        )
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Cylinder(RenderCylinderSerial {
+            material: self.material.name.clone(),
+            org: self.org,
+            axis: self.axis,
+            r: self.r,
+            height: self.height,
+            uvmap: self.uvmap,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderConeSerial {
+    material: String,
+    org: Vec3, /* Center of the base */
+    axis: Vec3,
+    r: f32,
+    #[serde(default)]
+    top_r: f32,
+    height: f32,
+    #[serde(default)]
+    uvmap: UVMap,
+}
+
+/// A cone with its apex cut off by a cap of radius `top_r`, which is a complete cone if
+/// `top_r` is 0.
+#[derive(Clone)]
+pub struct RenderCone {
+    material: Arc<RenderMaterial>,
+    org: Vec3,  /* Center of the base */
+    axis: Vec3, /* Unit vector from the base to the top */
+    r: f32,     /* Radius of the base */
+    top_r: f32, /* Radius of the top */
+    height: f32,
+    uvmap: UVMap,
+}
+
+impl RenderCone {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        height: f32,
+    ) -> RenderObject {
+        RenderObject::Cone(RenderCone::new_raw(material, org, axis, r, height))
+    }
+
+    pub fn new_raw(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        height: f32,
+    ) -> RenderCone {
+        RenderCone {
+            material,
+            org,
+            axis: axis.normalized(),
+            r,
+            top_r: 0.,
+            height,
+            uvmap: UVMap::XY,
+        }
+    }
+
+    pub fn top_r(mut self, top_r: f32) -> Self {
+        self.top_r = top_r;
+        self
+    }
+
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderConeSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Cone(
+            Self::new_raw(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderCone couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                serial.org,
+                serial.axis,
+                serial.r,
+                serial.height,
+            )
+            .top_r(serial.top_r)
+            .uvmap(serial.uvmap),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderCone {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.material
+            .lookup_texture(self.material.get_uv(&(position - &self.org), self.uvmap))
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        frustum_normal(
+            &(position - &self.org),
+            &self.axis,
+            self.height,
+            self.r,
+            self.top_r,
+        )
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        match frustum_interval(
+            &(vi - &self.org),
+            eye,
+            &self.axis,
+            self.height,
+            self.r,
+            self.top_r,
+        ) {
+            Some((t0, t1)) => select_hit(t0, t1, ray_length, flags),
+            None => ray_length,
+        }
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        frustum_distance(
+            &(vi - &self.org),
+            &self.axis,
+            self.height,
+            self.r,
+            self.top_r,
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.org + self.axis * self.height;
+        Some(
+            disk_bounds(&self.org, &self.axis, self.r)
+                .union(&disk_bounds(&top, &self.axis, self.top_r)),
+        )
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Cone(RenderConeSerial {
+            material: self.material.name.clone(),
+            org: self.org,
+            axis: self.axis,
+            r: self.r,
+            top_r: self.top_r,
+            height: self.height,
+            uvmap: self.uvmap,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderDiskSerial {
+    material: String,
+    org: Vec3, /* Center */
+    face_normal: Vec3,
+    r: f32,
+    #[serde(default)]
+    uvmap: UVMap,
+}
+
+/// A flat circle without thickness. Unlike the other objects it has no inside, so it can be
+/// hit from either side but never exited.
+#[derive(Clone)]
+pub struct RenderDisk {
+    material: Arc<RenderMaterial>,
+    org: Vec3, /* Center */
+    face_normal: Vec3,
+    r: f32,
+    uvmap: UVMap,
+}
+
+impl RenderDisk {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        face_normal: Vec3,
+        r: f32,
+    ) -> RenderObject {
+        RenderObject::Disk(RenderDisk::new_raw(material, org, face_normal, r))
+    }
+
+    pub fn new_raw(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        face_normal: Vec3,
+        r: f32,
+    ) -> RenderDisk {
+        RenderDisk {
+            material,
+            org,
+            face_normal: face_normal.normalized(),
+            r,
+            uvmap: UVMap::XY,
+        }
+    }
+
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderDiskSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Disk(
+            Self::new_raw(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderDisk couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                serial.org,
+                serial.face_normal,
+                serial.r,
+            )
+            .uvmap(serial.uvmap),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderDisk {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.material
+            .lookup_texture(self.material.get_uv(&(position - &self.org), self.uvmap))
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, _: &Vec3) -> Vec3 {
+        self.face_normal
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        let w = self.face_normal.dot(eye);
+        if 0 != (flags & OUTONLY) || w == 0. {
+            return ray_length;
+        }
+        let wpt = vi - &self.org;
+        let t0 = -self.face_normal.dot(&wpt) / w;
+        if t0 >= 0. && t0 < ray_length && (wpt + *eye * t0).squared_len() <= self.r * self.r {
+            t0
+        } else {
+            ray_length
+        }
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        let (h, perp) = axial(&(vi - &self.org), &self.face_normal);
+        let rho = (perp.len() - self.r).max(0.);
+        (rho * rho + h * h).sqrt()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(disk_bounds(&self.org, &self.face_normal, self.r))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Disk(RenderDiskSerial {
+            material: self.material.name.clone(),
+            org: self.org,
+            face_normal: self.face_normal,
+            r: self.r,
+            uvmap: self.uvmap,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderTorusSerial {
+    material: String,
+    org: Vec3, /* Center */
+    axis: Vec3,
+    r: f32,
+    tube_r: f32,
+    #[serde(default)]
+    uvmap: UVMap,
+}
+
+#[derive(Clone)]
+pub struct RenderTorus {
+    material: Arc<RenderMaterial>,
+    org: Vec3,   /* Center */
+    axis: Vec3,  /* Unit normal of the plane of the ring */
+    r: f32,      /* Radius of the ring */
+    tube_r: f32, /* Radius of the tube around the ring */
+    uvmap: UVMap,
+}
+
+impl RenderTorus {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        tube_r: f32,
+    ) -> RenderObject {
+        RenderObject::Torus(RenderTorus::new_raw(material, org, axis, r, tube_r))
+    }
+
+    pub fn new_raw(
+        material: Arc<RenderMaterial>,
+        org: Vec3,
+        axis: Vec3,
+        r: f32,
+        tube_r: f32,
+    ) -> RenderTorus {
+        RenderTorus {
+            material,
+            org,
+            axis: axis.normalized(),
+            r,
+            tube_r,
+            uvmap: UVMap::XY,
+        }
+    }
+
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderTorusSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Torus(
+            Self::new_raw(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderTorus couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                serial.org,
+                serial.axis,
+                serial.r,
+                serial.tube_r,
+            )
+            .uvmap(serial.uvmap),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderTorus {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.material
+            .lookup_texture(self.material.get_uv(&(position - &self.org), self.uvmap))
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        let w = position - &self.org;
+        let (_, perp) = axial(&w, &self.axis);
+        if perp.squared_len() == 0. {
+            return self.axis;
+        }
+        // Away from the nearest point on the ring
+        (w - perp.normalized() * self.r).normalized()
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        let wpt = vi - &self.org;
+
+        // Clip by the bounding sphere first, and start solving from where the ray enters it.
+        // The quartic's coefficients grow with the fourth power of the distance, so starting
+        // close to the torus keeps enough precision.
+        let outer = self.r + self.tube_r;
+        let dd = eye.squared_len();
+        let b = eye.dot(&wpt);
+        let d2 = b * b - dd * (wpt.squared_len() - outer * outer);
+        if d2 < 0. {
+            return ray_length;
+        }
+        let start = ((-b - d2.sqrt()) / dd).max(0.);
+        let w = wpt + *eye * start;
+
+        let (r2, dd) = ((self.r * self.r) as f64, dd as f64);
+        let (ww, wd) = (w.squared_len() as f64, w.dot(eye) as f64);
+        let (wa, da) = (w.dot(&self.axis) as f64, eye.dot(&self.axis) as f64);
+        let e = ww + r2 - (self.tube_r * self.tube_r) as f64;
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (|p|^2 - (p . axis)^2) along p = w + t * eye
+        let roots = solve_quartic(
+            dd * dd,
+            4. * dd * wd,
+            4. * wd * wd + 2. * dd * e - 4. * r2 * (dd - da * da),
+            4. * wd * e - 8. * r2 * (wd - wa * da),
+            e * e - 4. * r2 * (ww - wa * wa),
+        );
+
+        // The roots alternate between entering and exiting the tube
+        for (i, root) in roots.into_iter().enumerate() {
+            let t = start + root as f32;
+            let hit = if i % 2 == 0 {
+                0 == (flags & OUTONLY) && t >= 0.
+            } else {
+                0 == (flags & INONLY) && 0. < t
+            };
+            if t >= ray_length {
+                break;
+            } else if hit {
+                return t;
+            }
+        }
+        ray_length
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        let (h, perp) = axial(&(vi - &self.org), &self.axis);
+        let rho = perp.len() - self.r;
+        (rho * rho + h * h).sqrt() - self.tube_r
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bounds = disk_bounds(&self.org, &self.axis, self.r);
+        let t = Vec3::new(self.tube_r, self.tube_r, self.tube_r);
+        Some(Aabb::new(bounds.min - t, bounds.max + t))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Torus(RenderTorusSerial {
+            material: self.material.name.clone(),
+            org: self.org,
+            axis: self.axis,
+            r: self.r,
+            tube_r: self.tube_r,
+            uvmap: self.uvmap,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// The left operand with the right one carved out
+    Difference,
+}
+
+impl CsgOp {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOp::Union => in_left || in_right,
+            CsgOp::Intersection => in_left && in_right,
+            CsgOp::Difference => in_left && !in_right,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderCsgSerial {
+    op: CsgOp,
+    left: Box<RenderObjectSerial>,
+    right: Box<RenderObjectSerial>,
+}
+
+/// Upper limit of spans collected from an operand along a single ray
+const CSG_MAX_INTERVALS: usize = 16;
+
+/// Spans of the ray `vi + t * eye` inside `obj`, found by alternately searching for an exit
+/// with `OUTONLY` and an entry with `INONLY`. A span starting behind the ray origin begins at
+/// negative infinity.
+fn ray_intervals(
+    obj: &dyn RenderObjectInterface,
+    vi: &Vec3,
+    eye: &Vec3,
+    ray_length: f32,
+) -> Vec<(f32, f32)> {
+    let inf = f32::INFINITY;
+    let mut ret = vec![];
+    let mut entry = if obj.raycast(vi, eye, inf, OUTONLY) < obj.raycast(vi, eye, inf, INONLY) {
+        Some(-inf)
+    } else {
+        None
+    };
+    let mut t = 0.;
+    while t < ray_length && ret.len() < CSG_MAX_INTERVALS {
+        let pos = *vi + *eye * t;
+        match entry {
+            Some(t0) => {
+                t += obj.raycast(&pos, eye, inf, OUTONLY);
+                ret.push((t0, t));
+                entry = None;
+            }
+            None => {
+                t += obj.raycast(&pos, eye, inf, INONLY);
+                entry = Some(t);
+            }
+        }
+    }
+    ret
+}
+
+/// Applies a boolean operation to two sorted lists of spans.
+fn combine_intervals(op: CsgOp, left: &[(f32, f32)], right: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let inside = |list: &[(f32, f32)], t: f32| list.iter().any(|&(t0, t1)| t0 <= t && t < t1);
+    let mut bounds: Vec<f32> = left
+        .iter()
+        .chain(right)
+        .flat_map(|&(t0, t1)| [t0, t1])
+        .collect();
+    bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    bounds.dedup();
+
+    let mut ret: Vec<(f32, f32)> = vec![];
+    for pair in bounds.windows(2) {
+        let (t0, t1) = (pair[0], pair[1]);
+        // Classify each elementary segment by a point inside it
+        let mid = match (t0.is_finite(), t1.is_finite()) {
+            (true, true) => (t0 + t1) / 2.,
+            (false, true) => t1 - 1.,
+            (true, false) => t0 + 1.,
+            (false, false) => 0.,
+        };
+        if op.contains(inside(left, mid), inside(right, mid)) {
+            match ret.last_mut() {
+                Some(last) if last.1 == t0 => last.1 = t1,
+                _ => ret.push((t0, t1)),
+            }
+        }
+    }
+    ret
+}
+
+/// Constructive solid geometry node combining two objects. Surface properties are taken from
+/// the operand whose surface is hit, except for `get_material`, which can't tell which one it
+/// is and returns the left operand's material.
+#[derive(Clone)]
+pub struct RenderCsg {
+    op: CsgOp,
+    left: Box<RenderObject>,
+    right: Box<RenderObject>,
+}
+
+impl RenderCsg {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(op: CsgOp, left: RenderObject, right: RenderObject) -> RenderObject {
+        RenderObject::Csg(RenderCsg::new_raw(op, left, right))
+    }
+
+    pub fn new_raw(op: CsgOp, left: RenderObject, right: RenderObject) -> RenderCsg {
+        RenderCsg {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderCsgSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Csg(Self::new_raw(
+            serial.op,
+            ren.deserialize_object(&serial.left)?,
+            ren.deserialize_object(&serial.right)?,
+        )))
+    }
+
+    /// Returns the operand whose surface is closest to `position`, and whether it is the right
+    /// one.
+    fn surface(&self, position: &Vec3) -> (&dyn RenderObjectInterface, bool) {
+        let (left, right) = (self.left.get_interface(), self.right.get_interface());
+        if right.distance(position).abs() < left.distance(position).abs() {
+            (right, true)
+        } else {
+            (left, false)
+        }
+    }
+}
+
+impl RenderObjectInterface for RenderCsg {
+    fn get_material(&self) -> &RenderMaterial {
+        self.left.get_interface().get_material()
+    }
+
+    fn get_material_at(&self, position: &Vec3) -> &RenderMaterial {
+        self.surface(position).0.get_material_at(position)
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.surface(position).0.get_diffuse(position)
+    }
+
+    fn get_specular(&self, position: &Vec3) -> RenderColor {
+        self.surface(position).0.get_specular(position)
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        let (obj, is_right) = self.surface(position);
+        let n = obj.get_normal(position);
+        // The carved out surface faces into the right operand
+        if is_right && self.op == CsgOp::Difference {
+            n * -1.
+        } else {
+            n
+        }
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        let left = ray_intervals(self.left.get_interface(), vi, eye, ray_length);
+        if left.is_empty() && self.op != CsgOp::Union {
+            return ray_length;
+        }
+        let right = ray_intervals(self.right.get_interface(), vi, eye, ray_length);
+        for (t0, t1) in combine_intervals(self.op, &left, &right) {
+            let t = select_hit(t0, t1, ray_length, flags);
+            if t < ray_length {
+                return t;
+            }
+        }
+        ray_length
+    }
+
+    fn supports_raycast(&self) -> bool {
+        self.left.get_interface().supports_raycast()
+            && self.right.get_interface().supports_raycast()
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        let left = self.left.get_interface().distance(vi);
+        let right = self.right.get_interface().distance(vi);
+        match self.op {
+            CsgOp::Union => left.min(right),
+            CsgOp::Intersection => left.max(right),
+            CsgOp::Difference => left.max(-right),
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let left = self.left.get_interface().bounding_box();
+        let right = self.right.get_interface().bounding_box();
+        match self.op {
+            CsgOp::Union => Some(left?.union(&right?)),
+            CsgOp::Intersection => match (left, right) {
+                (Some(l), Some(r)) => Some(Aabb::new(
+                    Vec3::new(
+                        l.min.x.max(r.min.x),
+                        l.min.y.max(r.min.y),
+                        l.min.z.max(r.min.z),
+                    ),
+                    Vec3::new(
+                        l.max.x.min(r.max.x),
+                        l.max.y.min(r.max.y),
+                        l.max.z.min(r.max.z),
+                    ),
+                )),
+                (l, r) => l.or(r),
+            },
+            CsgOp::Difference => left,
+        }
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Csg(RenderCsgSerial {
+            op: self.op,
+            left: Box::new(self.left.get_interface().serialize()),
+            right: Box::new(self.right.get_interface().serialize()),
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderTransformedSerial {
+    object: Box<RenderObjectSerial>,
+    #[serde(default)]
+    transform: Transform,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderInstanceSerial {
+    geometry: String, /* Key in the scene's geometries */
+    #[serde(default)]
+    transform: Transform,
+}
+
+/// An object placed by its own translation, rotation and scale. Rays are transformed into the
+/// object's space and normals back out. The object is either owned by this instance alone, or
+/// one of `RenderEnv::geometries` shared by any number of instances.
+#[derive(Clone)]
+pub struct RenderInstance {
+    geometry: Option<String>, /* Name of the shared geometry, if any */
+    object: Arc<RenderObject>,
+    transform: Transform,
+}
+
+impl RenderInstance {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(object: Arc<RenderObject>, transform: Transform) -> RenderObject {
+        RenderObject::Instance(RenderInstance::new_raw(object, transform))
+    }
+
+    pub fn new_raw(object: Arc<RenderObject>, transform: Transform) -> RenderInstance {
+        RenderInstance {
+            geometry: None,
+            object,
+            transform,
+        }
+    }
+
+    /// Refer to the object as a shared geometry with the given name when serialized.
+    pub fn geometry(mut self, name: &str) -> Self {
+        self.geometry = Some(name.to_string());
+        self
+    }
+
+    fn deserialize_transformed(
+        ren: &RenderEnv,
+        serial: &RenderTransformedSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Instance(Self::new_raw(
+            Arc::new(ren.deserialize_object(&serial.object)?),
+            serial.transform,
+        )))
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderInstanceSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Instance(
+            Self::new_raw(
+                ren.geometries
+                    .get(&serial.geometry)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderInstance couldn't find geometry {}",
+                            serial.geometry
+                        ))
+                    })?
+                    .clone(),
+                serial.transform,
+            )
+            .geometry(&serial.geometry),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderInstance {
+    fn get_material(&self) -> &RenderMaterial {
+        self.object.get_interface().get_material()
+    }
+
+    fn get_material_at(&self, position: &Vec3) -> &RenderMaterial {
+        self.object
+            .get_interface()
+            .get_material_at(&self.transform.inverse_point(position))
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.get_hit_diffuse(position, None)
+    }
+
+    fn get_hit_diffuse(&self, position: &Vec3, hit: Option<MeshHit>) -> RenderColor {
+        self.object
+            .get_interface()
+            .get_hit_diffuse(&self.transform.inverse_point(position), hit)
+    }
+
+    fn get_specular(&self, position: &Vec3) -> RenderColor {
+        self.object
+            .get_interface()
+            .get_specular(&self.transform.inverse_point(position))
+    }
+
+    fn get_normal(&self, position: &Vec3) -> Vec3 {
+        self.get_hit_normal(position, None)
+    }
+
+    fn get_hit_normal(&self, position: &Vec3, hit: Option<MeshHit>) -> Vec3 {
+        let n = self
+            .object
+            .get_interface()
+            .get_hit_normal(&self.transform.inverse_point(position), hit);
+        self.transform.apply_normal(&n)
+    }
+
+    fn raycast(&self, vi: &Vec3, eye: &Vec3, ray_length: f32, flags: u32) -> f32 {
+        self.raycast_hit(vi, eye, ray_length, flags).0
+    }
+
+    fn raycast_hit(
+        &self,
+        vi: &Vec3,
+        eye: &Vec3,
+        ray_length: f32,
+        flags: u32,
+    ) -> (f32, Option<MeshHit>) {
+        // Objects expect a unit direction, so lengths along the ray are rescaled by how much the
+        // transform stretches it.
+        let local_eye = self.transform.inverse_vector(eye);
+        let stretch = local_eye.len();
+        if stretch == 0. {
+            return (ray_length, None);
+        }
+        let local_length = ray_length * stretch;
+        let (t, hit) = self.object.get_interface().raycast_hit(
+            &self.transform.inverse_point(vi),
+            &(local_eye * (1. / stretch)),
+            local_length,
+            flags,
+        );
+        if t < local_length {
+            (t / stretch, hit)
+        } else {
+            (ray_length, None)
+        }
+    }
+
+    fn supports_raycast(&self) -> bool {
+        self.object.get_interface().supports_raycast()
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        // Exact for uniform scale, and a lower bound otherwise which is enough for raymarching
+        let scale = &self.transform.scale;
+        let min_scale = scale.x.abs().min(scale.y.abs()).min(scale.z.abs());
+        self.object
+            .get_interface()
+            .distance(&self.transform.inverse_point(vi))
+            * min_scale
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bounds = self.object.get_interface().bounding_box()?;
+        let corners: Vec<_> = (0..8)
+            .map(|i| {
+                let corner = Vec3::new(
+                    if i & 1 == 0 {
+                        bounds.min.x
+                    } else {
+                        bounds.max.x
+                    },
+                    if i & 2 == 0 {
+                        bounds.min.y
+                    } else {
+                        bounds.max.y
+                    },
+                    if i & 4 == 0 {
+                        bounds.min.z
+                    } else {
+                        bounds.max.z
+                    },
+                );
+                self.transform.apply_point(&corner)
+            })
+            .collect();
+        Some(Aabb::from_points(&corners))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        match self.geometry {
+            Some(ref geometry) => RenderObjectSerial::Instance(RenderInstanceSerial {
+                geometry: geometry.clone(),
+                transform: self.transform,
+            }),
+            None => RenderObjectSerial::Transformed(RenderTransformedSerial {
+                object: Box::new(self.object.get_interface().serialize()),
+                transform: self.transform,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderSdfSerial {
+    material: String,
+    org: Vec3, /* Center */
+    shape: SdfShape,
+    #[serde(default)]
+    uvmap: UVMap,
+}
+
+/// An object only defined by a signed distance function, which can be ray marched but not
+/// ray traced.
+#[derive(Clone)]
+pub struct RenderSdf {
+    material: Arc<RenderMaterial>,
+    org: Vec3, /* Center */
+    shape: SdfShape,
+    uvmap: UVMap,
+}
+
+impl RenderSdf {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(material: Arc<RenderMaterial>, org: Vec3, shape: SdfShape) -> RenderObject {
+        RenderObject::Sdf(RenderSdf::new_raw(material, org, shape))
+    }
+
+    pub fn new_raw(material: Arc<RenderMaterial>, org: Vec3, shape: SdfShape) -> RenderSdf {
+        RenderSdf {
+            material,
+            org,
+            shape,
+            uvmap: UVMap::XY,
+        }
+    }
+
+    pub fn uvmap(mut self, uvmap: UVMap) -> Self {
+        self.uvmap = uvmap;
+        self
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderSdfSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::Sdf(
+            Self::new_raw(
+                ren.materials
+                    .get(&serial.material)
+                    .ok_or_else(|| {
+                        DeserializeError::new(&format!(
+                            "RenderSdf couldn't find material {}",
+                            serial.material
+                        ))
+                    })?
+                    .clone(),
+                serial.org,
+                serial.shape.clone(),
+            )
+            .uvmap(serial.uvmap),
+        ))
+    }
+}
+
+impl RenderObjectInterface for RenderSdf {
+    fn get_material(&self) -> &RenderMaterial {
+        &self.material
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        self.material
+            .lookup_texture(self.material.get_uv(&(position - &self.org), self.uvmap))
+    }
+
+    fn get_specular(&self, _position: &Vec3) -> RenderColor {
+        self.material.specular
+    }
+
+    fn raycast(&self, _vi: &Vec3, _eye: &Vec3, ray_length: f32, _flags: u32) -> f32 {
+        // Never hit; render() refuses to ray trace scenes with this object
+        ray_length
+    }
+
+    fn supports_raycast(&self) -> bool {
+        false
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        self.shape.distance(&(vi - &self.org))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bounds = self.shape.bounding_box()?;
+        Some(Aabb::new(bounds.min + self.org, bounds.max + self.org))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::Sdf(RenderSdfSerial {
+            material: self.material.name.clone(),
+            org: self.org,
+            shape: self.shape.clone(),
+            uvmap: self.uvmap,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderSmoothUnionSerial {
+    k: f32,
+    left: Box<RenderObjectSerial>,
+    right: Box<RenderObjectSerial>,
+}
+
+/// Union of two objects whose surfaces melt into each other where they are closer than `k`,
+/// by the smooth minimum of their distances. Colors blend the same way. Like `RenderSdf`, it
+/// can only be ray marched.
+#[derive(Clone)]
+pub struct RenderSmoothUnion {
+    k: f32,
+    left: Box<RenderObject>,
+    right: Box<RenderObject>,
+}
+
+impl RenderSmoothUnion {
+    #[allow(dead_code)]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(k: f32, left: RenderObject, right: RenderObject) -> RenderObject {
+        RenderObject::SmoothUnion(RenderSmoothUnion::new_raw(k, left, right))
+    }
+
+    pub fn new_raw(k: f32, left: RenderObject, right: RenderObject) -> RenderSmoothUnion {
+        RenderSmoothUnion {
+            k,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn deserialize(
+        ren: &RenderEnv,
+        serial: &RenderSmoothUnionSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        Ok(RenderObject::SmoothUnion(Self::new_raw(
+            serial.k,
+            ren.deserialize_object(&serial.left)?,
+            ren.deserialize_object(&serial.right)?,
+        )))
+    }
+
+    fn blend(&self, position: &Vec3) -> (f32, f32) {
+        smooth_min(
+            self.left.get_interface().distance(position),
+            self.right.get_interface().distance(position),
+            self.k,
+        )
+    }
+}
+
+impl RenderObjectInterface for RenderSmoothUnion {
+    fn get_material(&self) -> &RenderMaterial {
+        self.left.get_interface().get_material()
+    }
+
+    /// The material can't be blended, so it is that of the operand weighing the most.
+    fn get_material_at(&self, position: &Vec3) -> &RenderMaterial {
+        if 0.5 <= self.blend(position).1 {
+            self.left.get_interface().get_material_at(position)
+        } else {
+            self.right.get_interface().get_material_at(position)
+        }
+    }
+
+    fn get_diffuse(&self, position: &Vec3) -> RenderColor {
+        let h = self.blend(position).1;
+        self.left.get_interface().get_diffuse(position) * h
+            + self.right.get_interface().get_diffuse(position) * (1. - h)
+    }
+
+    fn get_specular(&self, position: &Vec3) -> RenderColor {
+        let h = self.blend(position).1;
+        self.left.get_interface().get_specular(position) * h
+            + self.right.get_interface().get_specular(position) * (1. - h)
+    }
+
+    fn raycast(&self, _vi: &Vec3, _eye: &Vec3, ray_length: f32, _flags: u32) -> f32 {
+        ray_length
+    }
+
+    fn supports_raycast(&self) -> bool {
+        false
+    }
+
+    fn distance(&self, vi: &Vec3) -> f32 {
+        self.blend(vi).0
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // The blend bulges out by at most a quarter of k
+        let bounds = self
+            .left
+            .get_interface()
+            .bounding_box()?
+            .union(&self.right.get_interface().bounding_box()?);
+        let margin = Vec3::new(self.k, self.k, self.k) * 0.25;
+        Some(Aabb::new(bounds.min - margin, bounds.max + margin))
+    }
+
+    fn serialize(&self) -> RenderObjectSerial {
+        RenderObjectSerial::SmoothUnion(RenderSmoothUnionSerial {
+            k: self.k,
+            left: Box::new(self.left.get_interface().serialize()),
+            right: Box::new(self.right.get_interface().serialize()),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub enum RenderObject {
+    Sphere(RenderSphere),
+    Floor(RenderFloor),
+    Mesh(RenderMesh),
+    Box(RenderBox),
+    Cylinder(RenderCylinder),
+    Cone(RenderCone),
+    Disk(RenderDisk),
+    Torus(RenderTorus),
+    Csg(RenderCsg),
+    Instance(RenderInstance),
+    Sdf(RenderSdf),
+    SmoothUnion(RenderSmoothUnion),
+}
+
+impl RenderObject {
+    pub fn get_interface(&self) -> &dyn RenderObjectInterface {
+        match self {
+            RenderObject::Sphere(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Floor(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Mesh(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Box(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Cylinder(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Cone(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Disk(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Torus(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Csg(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Instance(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::Sdf(ref obj) => obj as &dyn RenderObjectInterface,
+            RenderObject::SmoothUnion(ref obj) => obj as &dyn RenderObjectInterface,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct CameraSerial {
+    position: Vec3,
+    pyr: Vec3,
+    #[serde(default)]
+    aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    focus_distance: f32,
+}
+
+fn default_focus_distance() -> f32 {
+    DEFAULT_FOCUS_DISTANCE
+}
+
+fn default_glossy_samples() -> u32 {
+    GLOSSY_SAMPLES
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct CameraKeyframeSerial {
+    camera: CameraSerial,
+    velocity: Vec3,
+    camera_target: Option<Vec3>,
+    duration: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CameraMotionSerial(Vec<CameraKeyframeSerial>);
+
+#[derive(Clone, Copy)]
+pub struct Camera {
+    pub position: Vec3,
+    pub pyr: Vec3,
+    pub rotation: Quat,
+    /// Radius of the thin lens. Zero makes a pinhole camera with everything in focus.
+    pub aperture: f32,
+    /// Distance along the view direction to the plane that is in perfect focus.
+    pub focus_distance: f32,
+}
+
+impl From<&Camera> for CameraSerial {
+    fn from(o: &Camera) -> CameraSerial {
+        CameraSerial {
+            position: o.position,
+            pyr: o.pyr,
+            aperture: o.aperture,
+            focus_distance: o.focus_distance,
+        }
+    }
+}
+
+impl From<CameraSerial> for Camera {
+    fn from(o: CameraSerial) -> Camera {
+        Camera {
+            position: o.position,
+            pyr: o.pyr,
+            rotation: Quat::from_pyr(&o.pyr),
+            aperture: o.aperture,
+            focus_distance: o.focus_distance,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CameraKeyframe {
+    pub camera: Camera,
+    pub velocity: Vec3,
+    camera_target: Option<Vec3>,
+    pub duration: f32,
+}
+
+#[derive(Clone)]
+pub struct CameraMotion(pub Vec<CameraKeyframe>);
+
+/// Method of computing the color seen along a camera ray.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RenderMode {
+    /// Whitted style ray tracing with analytic intersections
+    #[default]
+    RayTrace,
+    /// Whitted style shading on surfaces found by sphere tracing distance functions
+    RayMarch,
+    /// Monte Carlo path tracing with global illumination from diffuse bounces
+    PathTrace,
+}
+
+/// Settings of `RenderMode::PathTrace`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathTracing {
+    /// Number of images averaged by progressive rendering, each tracing
+    /// `AntiAliasing::samples` paths per pixel.
+    pub passes: u32,
+    /// Bounces after which paths are terminated randomly by Russian roulette, with a
+    /// probability that keeps the result unbiased.
+    pub roulette_depth: i32,
+    /// Hard limit of bounces for paths trapped between mirrors, which would otherwise rarely
+    /// be terminated.
+    pub max_bounces: i32,
+}
+
+impl Default for PathTracing {
+    fn default() -> Self {
+        Self {
+            passes: 16,
+            roulette_depth: 3,
+            max_bounces: 64,
+        }
+    }
+}
+
+/// Darkening of the ambient light where nearby surfaces cover the sky above a point.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AmbientOcclusion {
+    /// Rays cast over the hemisphere by the ray tracer, or steps away from the surface taken by
+    /// the ray marcher.
+    pub samples: u32,
+    /// Surfaces farther than this don't occlude.
+    pub radius: f32,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        Self {
+            samples: 16,
+            radius: 100.,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RenderEnv {
+    pub camera: Camera, /* camera position */
+    pub camera_motion: CameraMotion,
+    pub xres: i32,
+    pub yres: i32,
+    pub xfov: f32,
+    pub yfov: f32,
+    // Materials are stored in a string map, whose key is a string.
+    // A material is stored in Arc in order to share between global material list
+    // and each object. I'm not sure if it's better than embedding into each object.
+    // We wanted to but cannot use reference (borrow checker gets mad about enums)
+    // nor Rc (multithreading gets mad).
+    pub materials: HashMap<String, Arc<RenderMaterial>>,
+    /// Objects that are not rendered by themselves but placed by `RenderInstance`s, so that
+    /// they can appear many times without being duplicated.
+    pub geometries: HashMap<String, Arc<RenderObject>>,
+    /// Changed only through `objects` and `push_object`, which keep `bvh` up to date.
+    objects: Vec<RenderObject>,
+    /// Acceleration structure over `objects`.
+    bvh: Bvh,
+    /// The smallest nonzero glow_dist among materials of `objects`, used to cull the BVH in
+    /// distance queries.
+    min_glow_dist: f32,
+    pub lights: Vec<RenderLight>,
+    pub background: Background,
+    pub render_mode: RenderMode,
+    pub path_tracing: PathTracing,
+    /// Occludes the constant ambient term of ray tracing and ray marching when set. The path
+    /// tracer doesn't need it, as its diffuse bounces find the occluders anyway.
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    glow_effect: Option<f32>,
+    pub max_reflections: i32,
+    pub max_refractions: i32,
+    /// Rays averaged for a reflection on a rough surface
+    pub glossy_samples: u32,
+    /// Directory that relative file paths in deserialized scenes are resolved against, which
+    /// is the current directory if empty.
+    pub scene_dir: PathBuf,
+    pub antialiasing: AntiAliasing,
+    pub tone_mapping: ToneMapping,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Scene {
+    camera: CameraSerial,
+    camera_motion: CameraMotionSerial,
+    max_reflections: i32,
+    max_refractions: i32,
+    #[serde(default = "default_glossy_samples")]
+    glossy_samples: u32,
+    // Sorted so that the output is stable
+    materials: BTreeMap<String, RenderMaterialSerial>,
+    // Deserialized in the order of names, so a geometry can only instance the ones sorting
+    // before it.
+    #[serde(default)]
+    geometries: BTreeMap<String, RenderObjectSerial>,
+    objects: Vec<RenderObjectSerial>,
+    // Older scene files have no lights, in which case the current ones are kept.
+    lights: Option<Vec<RenderLight>>,
+    #[serde(default)]
+    render_mode: RenderMode,
+    // Older scene files select ray marching by this flag instead of render_mode.
+    #[serde(default, skip_serializing)]
+    use_raymarching: bool,
+    #[serde(default)]
+    path_tracing: PathTracing,
+    #[serde(default)]
+    ambient_occlusion: Option<AmbientOcclusion>,
+    #[serde(default)]
+    glow_effect: Option<f32>,
+    // Omitted for custom backgrounds, in which case the current one is kept.
+    background: Option<BackgroundSerial>,
+    #[serde(default)]
+    antialiasing: AntiAliasing,
+    #[serde(default)]
+    tone_mapping: ToneMapping,
+}
+
+impl RenderEnv {
+    pub fn new(
+        cam: Vec3, /* camera position */
+        pyr: Vec3, /* camera direction in pitch yaw roll form */
+        xres: i32,
+        yres: i32,
+        xfov: f32,
+        yfov: f32,
+        background: Background,
+    ) -> Self {
+        RenderEnv {
+            camera: Camera {
+                position: cam,
+                pyr,
+                rotation: Quat::from_pyr(&pyr),
+                aperture: 0.,
+                focus_distance: DEFAULT_FOCUS_DISTANCE,
             },
-            camera_motion: CameraMotionSerial(vec![]),
+            camera_motion: CameraMotion(vec![]),
+            xres,
+            yres,
+            xfov,
+            yfov,
+            materials: HashMap::new(),
+            geometries: HashMap::new(),
+            objects: Vec::new(),
+            bvh: Bvh::build(&[]),
+            min_glow_dist: f32::INFINITY,
+            lights: vec![RenderLight::directional(Vec3::new(0., 0., 1.))],
+            background,
+            render_mode: RenderMode::default(),
+            path_tracing: PathTracing::default(),
+            ambient_occlusion: None,
+            glow_effect: None,
             max_reflections: MAX_REFLECTIONS,
             max_refractions: MAX_REFRACTIONS,
-            materials: HashMap::new(),
+            glossy_samples: GLOSSY_SAMPLES,
+            scene_dir: PathBuf::new(),
+            antialiasing: AntiAliasing::default(),
+            tone_mapping: ToneMapping::default(),
+        }
+    }
+
+    pub fn materials(mut self, materials: HashMap<String, Arc<RenderMaterial>>) -> Self {
+        self.materials = materials;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn geometries(mut self, geometries: HashMap<String, Arc<RenderObject>>) -> Self {
+        self.geometries = geometries;
+        self
+    }
+
+    pub fn objects(mut self, objects: Vec<RenderObject>) -> Self {
+        self.objects = objects;
+        self.update_bvh();
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn get_objects(&self) -> &[RenderObject] {
+        &self.objects
+    }
+
+    #[allow(dead_code)]
+    pub fn push_object(&mut self, object: RenderObject) {
+        self.objects.push(object);
+        self.update_bvh();
+    }
+
+    /// Rebuild the bounding volume hierarchy. Needs to be called whenever `objects` changes.
+    fn update_bvh(&mut self) {
+        let bounds: Vec<_> = self
+            .objects
+            .iter()
+            .map(|o| o.get_interface().bounding_box())
+            .collect();
+        self.bvh = Bvh::build(&bounds);
+        self.min_glow_dist = self
+            .objects
+            .iter()
+            .map(|o| o.get_interface().get_material().glow_dist)
+            .filter(|v| 0. < *v)
+            .fold(f32::INFINITY, f32::min);
+    }
+
+    pub fn lights(mut self, lights: Vec<RenderLight>) -> Self {
+        self.lights = lights;
+        self
+    }
+
+    pub fn render_mode(mut self, mode: RenderMode) -> Self {
+        self.render_mode = mode;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn path_tracing(mut self, path_tracing: PathTracing) -> Self {
+        self.path_tracing = path_tracing;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn ambient_occlusion(mut self, ambient_occlusion: Option<AmbientOcclusion>) -> Self {
+        self.ambient_occlusion = ambient_occlusion;
+        self
+    }
+
+    pub fn glow_effect(mut self, v: Option<f32>) -> Self {
+        self.glow_effect = v;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn antialiasing(mut self, antialiasing: AntiAliasing) -> Self {
+        self.antialiasing = antialiasing;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn tone_mapping(mut self, tone_mapping: ToneMapping) -> Self {
+        self.tone_mapping = tone_mapping;
+        self
+    }
+
+    pub fn serialize(&self) -> Result<String, std::io::Error> {
+        let mut sceneobj = Scene {
+            camera: CameraSerial::from(&self.camera),
+            camera_motion: CameraMotionSerial(
+                self.camera_motion
+                    .0
+                    .iter()
+                    .map(|o| CameraKeyframeSerial {
+                        camera: CameraSerial::from(&o.camera),
+                        velocity: o.velocity,
+                        camera_target: o.camera_target,
+                        duration: o.duration,
+                    })
+                    .collect(),
+            ),
+            max_reflections: self.max_reflections,
+            max_refractions: self.max_refractions,
+            glossy_samples: self.glossy_samples,
+            materials: BTreeMap::new(),
+            geometries: self
+                .geometries
+                .iter()
+                .map(|(name, o)| (name.clone(), o.get_interface().serialize()))
+                .collect(),
             objects: self
                 .objects
                 .iter()
                 .map(|o| o.get_interface().serialize())
                 .collect(),
+            lights: Some(self.lights.clone()),
+            render_mode: self.render_mode,
+            use_raymarching: false,
+            path_tracing: self.path_tracing,
+            ambient_occlusion: self.ambient_occlusion,
+            glow_effect: self.glow_effect,
+            background: self.background.serialize(),
+            antialiasing: self.antialiasing,
+            tone_mapping: self.tone_mapping,
         };
-        for object in &self.objects {
-            let material = object.get_interface().get_material();
-            sceneobj
-                .materials
-                .insert(material.name.clone(), material.serialize());
+        for object in self.geometries.values().map(|o| &**o).chain(&self.objects) {
+            collect_materials(object, &mut sceneobj.materials);
         }
         Ok(serde_yaml::to_string(&sceneobj)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?)
         // println!("{}", scene);
     }
 
+    fn deserialize_object(
+        &self,
+        object: &RenderObjectSerial,
+    ) -> Result<RenderObject, DeserializeError> {
+        match object {
+            RenderObjectSerial::Sphere(ref sobj) => RenderSphere::deserialize(self, sobj),
+            RenderObjectSerial::Floor(ref sobj) => RenderFloor::deserialize(self, sobj),
+            RenderObjectSerial::Mesh(ref sobj) => RenderMesh::deserialize(self, sobj),
+            RenderObjectSerial::Box(ref sobj) => RenderBox::deserialize(self, sobj),
+            RenderObjectSerial::Cylinder(ref sobj) => RenderCylinder::deserialize(self, sobj),
+            RenderObjectSerial::Cone(ref sobj) => RenderCone::deserialize(self, sobj),
+            RenderObjectSerial::Disk(ref sobj) => RenderDisk::deserialize(self, sobj),
+            RenderObjectSerial::Torus(ref sobj) => RenderTorus::deserialize(self, sobj),
+            RenderObjectSerial::Csg(ref sobj) => RenderCsg::deserialize(self, sobj),
+            RenderObjectSerial::Transformed(ref sobj) => {
+                RenderInstance::deserialize_transformed(self, sobj)
+            }
+            RenderObjectSerial::Instance(ref sobj) => RenderInstance::deserialize(self, sobj),
+            RenderObjectSerial::Sdf(ref sobj) => RenderSdf::deserialize(self, sobj),
+            RenderObjectSerial::SmoothUnion(ref sobj) => RenderSmoothUnion::deserialize(self, sobj),
+        }
+    }
+
     pub fn deserialize(&mut self, s: &str) -> Result<(), DeserializeError> {
         let sceneobj = serde_yaml::from_str::<Scene>(s)?;
         let mm: Result<HashMap<_, _>, DeserializeError> = sceneobj
@@ -782,46 +2997,138 @@ impl RenderEnv {
         );
         self.max_reflections = sceneobj.max_reflections;
         self.max_refractions = sceneobj.max_refractions;
+        self.glossy_samples = sceneobj.glossy_samples;
+        if let Some(lights) = sceneobj.lights {
+            self.lights = lights;
+        }
+        self.render_mode = if sceneobj.use_raymarching {
+            RenderMode::RayMarch
+        } else {
+            sceneobj.render_mode
+        };
+        self.path_tracing = sceneobj.path_tracing;
+        self.ambient_occlusion = sceneobj.ambient_occlusion;
+        self.glow_effect = sceneobj.glow_effect;
+        self.antialiasing = sceneobj.antialiasing;
+        self.tone_mapping = sceneobj.tone_mapping;
+        if let Some(ref background) = sceneobj.background {
+            self.background = Background::deserialize(background, &self.scene_dir)?;
+        }
         self.materials = mm?;
+        self.geometries.clear();
+        for (name, object) in sceneobj.geometries {
+            let object = self.deserialize_object(&object)?;
+            self.geometries.insert(name, Arc::new(object));
+        }
         self.objects.clear();
         for object in sceneobj.objects {
-            match object {
-                RenderObjectSerial::Sphere(ref sobj) => {
-                    self.objects.push(RenderSphere::deserialize(self, sobj)?)
-                }
-                RenderObjectSerial::Floor(ref sobj) => {
-                    self.objects.push(RenderFloor::deserialize(self, sobj)?)
-                }
-            }
+            let object = self.deserialize_object(&object)?;
+            self.objects.push(object);
         }
+        self.update_bvh();
         Ok(())
     }
 }
 
+/// Adds the materials of `object` and the objects it is composed of.
+fn collect_materials(
+    object: &RenderObject,
+    materials: &mut BTreeMap<String, RenderMaterialSerial>,
+) {
+    match object {
+        RenderObject::Csg(ref csg) => {
+            collect_materials(&csg.left, materials);
+            collect_materials(&csg.right, materials);
+        }
+        RenderObject::Instance(ref instance) => collect_materials(&instance.object, materials),
+        RenderObject::SmoothUnion(ref union) => {
+            collect_materials(&union.left, materials);
+            collect_materials(&union.right, materials);
+        }
+        _ => {
+            let material = object.get_interface().get_material();
+            materials.insert(material.name.clone(), material.serialize());
+        }
+    }
+}
+
+#[allow(dead_code)]
 pub fn render(
     ren: &RenderEnv,
     pointproc: &mut impl FnMut(i32, i32, &RenderColor),
     thread_count: i32,
 ) -> anyhow::Result<()> {
+    render_pass(ren, 0, &trace_color, pointproc, thread_count)
+}
+
+/// Color seen along the camera ray from `vi` in the direction `eye`, by the render mode.
+fn trace_color(ren: &RenderEnv, vi: &mut Vec3, eye: &mut Vec3, rng: &mut Rng) -> RenderColor {
+    match ren.render_mode {
+        RenderMode::RayTrace => raytrace(ren, vi, eye, 0, None, 0),
+        RenderMode::RayMarch => raymarch(ren, vi, eye, 0, None, 0),
+        RenderMode::PathTrace => pathtrace(ren, vi, eye, rng),
+    }
+}
+
+/// Renders the image once, with random numbers for the `pass`-th of progressively accumulated
+/// images. `trace` returns the value of a pixel seen along a camera ray.
+fn render_pass(
+    ren: &RenderEnv,
+    pass: u32,
+    trace: &(dyn Fn(&RenderEnv, &mut Vec3, &mut Vec3, &mut Rng) -> RenderColor + Sync),
+    pointproc: &mut impl FnMut(i32, i32, &RenderColor),
+    thread_count: i32,
+) -> anyhow::Result<()> {
+    if ren.render_mode != RenderMode::RayMarch {
+        if let Some(idx) = ren
+            .objects
+            .iter()
+            .position(|o| !o.get_interface().supports_raycast())
+        {
+            return Err(anyhow::anyhow!(
+                "Object {} is only defined by a distance function and needs ray marching",
+                idx
+            ));
+        }
+    }
+
+    let aa = &ren.antialiasing;
+
     let process_line = |iy: i32, point_middle: &mut dyn FnMut(i32, i32, RenderColor)| {
         for ix in 0..ren.xres {
-            let mut vi = ren.camera.position;
-            let mut eye: Vec3 = Vec3::new(
-                /* cast ray direction vector? */
-                1.,
-                (ix - ren.xres / 2) as f32 * 2. * ren.xfov / ren.xres as f32,
-                -(iy - ren.yres / 2) as f32 * 2. * ren.yfov / ren.yres as f32,
-            );
-            eye = ren.camera.rotation.transform(&eye).normalized();
+            let mut rng = Rng::new(((pass as u64) << 48) ^ (((iy as u64) << 32) | ix as u64));
+            let mut sum = RenderColor::zero();
+            let mut weights = 0.;
+            for i in 0..aa.samples.max(1) {
+                let (dx, dy, weight) = aa.sample(i, &mut rng);
+                let mut vi = ren.camera.position;
+                let mut eye: Vec3 = Vec3::new(
+                    /* cast ray direction vector? */
+                    1.,
+                    ((ix - ren.xres / 2) as f32 + dx) * 2. * ren.xfov / ren.xres as f32,
+                    -((iy - ren.yres / 2) as f32 + dy) * 2. * ren.yfov / ren.yres as f32,
+                );
+                if 0. < ren.camera.aperture {
+                    // Start from a random point on the lens and aim at the point on the focal
+                    // plane that the pinhole ray would hit, so that only that plane is sharp.
+                    let (u, v) = sample_disk(rng.next_f32(), rng.next_f32());
+                    let lens = Vec3::new(0., u, v) * ren.camera.aperture;
+                    vi += ren.camera.rotation.transform(&lens);
+                    eye = eye * ren.camera.focus_distance - lens;
+                }
+                eye = ren.camera.rotation.transform(&eye).normalized();
+                sum += trace(ren, &mut vi, &mut eye, &mut rng) * weight;
+                weights += weight;
+            }
 
             point_middle(
                 ix,
                 iy,
-                if ren.use_raymarching {
-                    raymarch
+                if 0. < weights {
+                    sum * (1. / weights)
                 } else {
-                    raytrace
-                }(ren, &mut vi, &mut eye, 0, None, 0),
+                    sum
+                },
             );
         }
     };
@@ -899,6 +3206,94 @@ pub fn render(
     Ok(())
 }
 
+/// Renders the whole image into a new framebuffer of `xres` by `yres` pixels.
+pub fn render_framebuffer(
+    ren: &RenderEnv,
+    thread_count: i32,
+) -> anyhow::Result<Framebuffer<RenderColor>> {
+    render_progressive(ren, &mut |_, _| (), thread_count)
+}
+
+/// Renders the image `path_tracing.passes` times with different random numbers when path
+/// tracing, and returns their average. `pass_proc` is called with the number of passes done and
+/// the average so far after each of them, so that the noise can be watched clearing up. Other
+/// modes are deterministic enough to render only once.
+pub fn render_progressive(
+    ren: &RenderEnv,
+    pass_proc: &mut impl FnMut(u32, &Framebuffer<RenderColor>),
+    thread_count: i32,
+) -> anyhow::Result<Framebuffer<RenderColor>> {
+    let passes = if ren.render_mode == RenderMode::PathTrace {
+        ren.path_tracing.passes.max(1)
+    } else {
+        1
+    };
+    let (width, height) = (ren.xres.max(0) as usize, ren.yres.max(0) as usize);
+    let mut sum = Framebuffer::new(width, height, RenderColor::zero());
+    let mut framebuffer = Framebuffer::new(width, height, RenderColor::zero());
+    for pass in 0..passes {
+        render_pass(
+            ren,
+            pass,
+            &trace_color,
+            &mut |x, y, c| {
+                let (x, y) = (x as usize, y as usize);
+                let total = sum.get(x, y) + *c;
+                sum.put(x, y, total);
+                framebuffer.put(x, y, total * (1. / (pass + 1) as f32));
+            },
+            thread_count,
+        )?;
+        pass_proc(pass + 1, &framebuffer);
+    }
+    Ok(framebuffer)
+}
+
+/// Renders the ambient occlusion of the surfaces seen by the camera as its own image, white where
+/// the ambient light is unoccluded and black where it is completely occluded, for compositing.
+/// Uses the default settings if the scene doesn't enable ambient occlusion.
+pub fn render_ao_framebuffer(
+    ren: &RenderEnv,
+    thread_count: i32,
+) -> anyhow::Result<Framebuffer<RenderColor>> {
+    let ao = ren.ambient_occlusion.unwrap_or_default();
+    let mut framebuffer = Framebuffer::new(
+        ren.xres.max(0) as usize,
+        ren.yres.max(0) as usize,
+        RenderColor::zero(),
+    );
+    render_pass(
+        ren,
+        0,
+        &|ren: &RenderEnv, vi: &mut Vec3, eye: &mut Vec3, _: &mut Rng| {
+            let hit = if ren.render_mode == RenderMode::RayMarch {
+                let result = raymarch_single(ren, vi, eye, None, None, f32::INFINITY);
+                if result.final_dist < RAYMARCH_EPS {
+                    Some((result.idx, result.pos, None))
+                } else {
+                    None
+                }
+            } else {
+                let (t, idx, hit) = raycast(ren, vi, eye, None, 0);
+                if t < f32::INFINITY {
+                    Some((idx, *eye * t + *vi, hit))
+                } else {
+                    None
+                }
+            };
+            let value = hit.map_or(1., |(idx, pt, hit)| {
+                let n = ren.objects[idx].get_interface().get_hit_normal(&pt, hit);
+                let facing = if 0. < eye.dot(&n) { n * -1. } else { n };
+                occlusion(ren, &pt, &facing, &ao)
+            });
+            RenderColor::new(value, value, value)
+        },
+        &mut |x, y, c| framebuffer.put(x as usize, y as usize, *c),
+        thread_count,
+    )?;
+    Ok(framebuffer)
+}
+
 // This warning is stupid, these variables are intermediate variables for the
 // function, so having long name wouldn't help to understand.  Anyone who needs
 // to understand what this function does needs to look into Hermite interpolation
@@ -925,11 +3320,9 @@ fn hermite_interpolate(t: f32, x0: &Vec3, x1: &Vec3, v0: &Vec3, v1: &Vec3) -> Ve
 
 pub fn render_frames(
     ren: &mut RenderEnv,
-    width: usize,
-    height: usize,
-    frame_proc: &mut impl FnMut(i32, &Vec<u8>),
+    frame_proc: &mut impl FnMut(i32, &Framebuffer<RenderColor>),
     thread_count: i32,
-) {
+) -> anyhow::Result<()> {
     let mut prev_camera = ren.camera;
     let mut prev_velocity = Vec3::zero();
     let total_frames = ren
@@ -958,6 +3351,10 @@ pub fn render_frames(
             );
             ren.camera.position =
                 hermite_interpolate(f, &prev_camera.position, &frame.camera.position, &v0, &v1);
+            ren.camera.aperture =
+                prev_camera.aperture + (frame.camera.aperture - prev_camera.aperture) * f;
+            ren.camera.focus_distance = prev_camera.focus_distance
+                + (frame.camera.focus_distance - prev_camera.focus_distance) * f;
             ren.camera.rotation = if let Some(target) = frame.camera_target {
                 let delta = target - ren.camera.position;
                 let pitch = (delta.y).atan2((delta.x * delta.x + delta.z * delta.z).sqrt());
@@ -968,71 +3365,347 @@ pub fn render_frames(
             } else {
                 prev_camera.rotation.slerp(&frame.camera.rotation, f)
             };
-            let data = {
-                let mut data = vec![0u8; 3 * width * height];
-                let mut putpoint = |x: i32, y: i32, fc: &RenderColor| {
-                    data[(x as usize + y as usize * width) * 3] = (fc.r * 255.).min(255.) as u8;
-                    data[(x as usize + y as usize * width) * 3 + 1] = (fc.g * 255.).min(255.) as u8;
-                    data[(x as usize + y as usize * width) * 3 + 2] = (fc.b * 255.).min(255.) as u8;
-                };
-
-                render(ren, &mut putpoint, thread_count);
-                data
-            };
-            frame_proc(accum_frame, &data);
+            let framebuffer = render_framebuffer(ren, thread_count)?;
+            frame_proc(accum_frame, &framebuffer);
             accum_frame += 1;
             // }
         }
         prev_camera = frame.camera;
         prev_velocity = frame.velocity;
     }
+    Ok(())
+}
+
+/* find first object the ray hits */
+/// @returns time at which ray intersects with a shape and its object id.
+fn raycast(
+    ren: &RenderEnv,
+    vi: &Vec3,
+    eye: &Vec3,
+    ig: Option<&RenderObject>,
+    flags: u32,
+) -> (f32, usize, Option<MeshHit>) {
+    let mut ret_idx = 0;
+    let mut ret_hit = None;
+
+    let t = ren.bvh.raycast(vi, eye, f32::INFINITY, |idx, t| {
+        let obj = &ren.objects[idx];
+        if let Some(ignore_obj) = ig {
+            if std::ptr::eq(ignore_obj, obj) {
+                return t;
+            }
+        }
+
+        let (obj_t, hit) = obj.get_interface().raycast_hit(vi, eye, t, flags);
+        if obj_t < t {
+            ret_idx = idx;
+            ret_hit = hit;
+            obj_t
+        } else {
+            t
+        }
+    });
+
+    (t, ret_idx, ret_hit)
+}
+
+/* shadow trace */
+/// Returns the share of the light `light_dist` away from `pt` in the direction `ray` that reaches
+/// `pt` in each channel. Opaque objects block the light, while transparent ones let it through
+/// except for what their surfaces reflect and their insides absorb, which casts tinted shadows.
+///
+/// When ray marching, a light of radius `size` is partially covered by objects that the ray
+/// toward its center passes by at an angle smaller than the angular radius of the light, which
+/// gives a penumbra without tracing rays to the rest of the light.
+fn shadow_transmittance(
+    ren: &RenderEnv,
+    idx: usize,
+    pt: &Vec3,
+    ray: &Vec3,
+    light_dist: f32,
+    size: f32,
+) -> RenderColor {
+    let eps = f32::EPSILON;
+    let mut pos = *pt + (*ray * eps);
+    let mut ig = Some(&ren.objects[idx]);
+    let mut remaining = light_dist;
+    let mut transmittance = RenderColor::new(1., 1., 1.);
+    for _ in 0..ren.max_refractions.max(0) * 2 + 1 {
+        if ren.render_mode == RenderMode::RayMarch {
+            let RaymarchSingleResult {
+                idx: hit,
+                pos: entry,
+                iter,
+                travel_dist,
+                min_ratio,
+                ..
+            } = raymarch_single(ren, &pos, ray, ig, None, remaining);
+            if remaining.min(FAR_AWAY) <= travel_dist || MAX_ITER <= iter {
+                // Only the ray toward the light from the last transparent object is compared
+                // with the size of the light, which is exact unless there are any.
+                let penumbra = if 0. < size {
+                    (min_ratio * light_dist / size).clamp(0., 1.)
+                } else {
+                    1.
+                };
+                return transmittance * penumbra;
+            }
+            let o = ren.objects[hit].get_interface();
+            if o.get_material_at(&entry).get_transparency() <= 0. {
+                return RenderColor::zero();
+            }
+            // Skip the inside at once, since marching out of it needs the negated distance
+            let n = o.get_normal(&entry);
+            let inside = inside_surface(o, &entry, &n);
+            let dist = interior_distance(o, &inside, ray);
+            if dist == f32::INFINITY {
+                return RenderColor::zero();
+            }
+            pos = inside + *ray * dist;
+            transmittance = absorb(o, &inside, ray, transmittance)
+                * (surface_transmittance(o, &entry, ray, &n)
+                    * surface_transmittance(o, &pos, ray, &o.get_normal(&pos)));
+            remaining -= travel_dist + dist;
+            ig = Some(&ren.objects[hit]);
+        } else {
+            let (t, hit, mesh_hit) = raycast(ren, &pos, ray, ig, 0);
+            if remaining <= t {
+                return transmittance;
+            }
+            let o = ren.objects[hit].get_interface();
+            let crossing = *ray * t + pos;
+            if o.get_material_at(&crossing).get_transparency() <= 0. {
+                return RenderColor::zero();
+            }
+            let n = o.get_hit_normal(&crossing, mesh_hit);
+            transmittance = transmittance * surface_transmittance(o, &crossing, ray, &n);
+            if ray.dot(&n) < 0. {
+                transmittance = absorb(o, &crossing, ray, transmittance);
+            }
+            // The same surface can't stop the ray again after moving a little past it
+            let offset = ray_offset(&crossing);
+            pos = crossing + *ray * offset;
+            remaining -= t + offset;
+            ig = None;
+        }
+    }
+    RenderColor::zero()
+}
+
+/// Share of light that passes straight through the surface of a transparent object `o` at `pt`
+/// with normal `n` along `ray`.
+fn surface_transmittance(o: &dyn RenderObjectInterface, pt: &Vec3, ray: &Vec3, n: &Vec3) -> f32 {
+    let material = o.get_material_at(pt);
+    let (reflectance, _) = dielectric(ray, n, material.get_refraction_index());
+    material.get_transparency() * (1. - reflectance)
+}
+
+/// Splits light hitting a dielectric boundary with outward normal `n` between reflection and
+/// refraction, where `frac` is the refraction index of the inside. Returns the Fresnel
+/// reflectance for unpolarized light and the refracted direction, or None on total internal
+/// reflection.
+fn dielectric(eye: &Vec3, n: &Vec3, frac: f32) -> (f32, Option<Vec3>) {
+    let cos_i = -eye.dot(n);
+    let (eta, normal, cos_i) = if 0. < cos_i {
+        (1. / frac, *n, cos_i)
+    } else {
+        (frac, *n * -1., -cos_i)
+    };
+    let sin2_t = eta * eta * (1. - cos_i * cos_i);
+    if 1. <= sin2_t {
+        return (1., None);
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let ray = (*eye * eta + normal * (eta * cos_i - cos_t)).normalized();
+    ((rs * rs + rp * rp) / 2., Some(ray))
+}
+
+/// Splits a physically based surface whose albedo is `base` into the weights of the diffuse
+/// and the specular layers seen along `eye`. The light reflected by the specular layer doesn't
+/// reach the diffuse layer, and metals have no diffuse layer at all.
+fn pbr_layers(
+    pbr: &PbrMaterial,
+    base: RenderColor,
+    n: &Vec3,
+    eye: &Vec3,
+) -> (RenderColor, RenderColor) {
+    let f = fresnel_schlick(pbr.f0(base), eye.dot(n).abs());
+    let kd = 1. - pbr.metallic;
+    (
+        RenderColor::new(
+            base.r * kd * (1. - f.r),
+            base.g * kd * (1. - f.g),
+            base.b * kd * (1. - f.b),
+        ),
+        f,
+    )
 }
 
-/* find first object the ray hits */
-/// @returns time at which ray intersects with a shape and its object id.
-fn raycast(
+/// Reflection weight of the surface at `pt`, which is the specular color plus the Fresnel
+/// reflection of the transparent part.
+fn reflectance(
     ren: &RenderEnv,
-    vi: &Vec3,
+    idx: usize,
+    n: &Vec3,
+    pt: &Vec3,
+    hit: Option<MeshHit>,
     eye: &Vec3,
-    ig: Option<&RenderObject>,
     flags: u32,
-) -> (f32, usize) {
-    let mut t = std::f32::INFINITY;
-    let mut ret_idx = 0;
-
-    for (idx, obj) in ren.objects.iter().enumerate() {
-        if let Some(ignore_obj) = ig {
-            if ignore_obj as *const _ == obj as *const _ {
-                continue;
-            }
+) -> RenderColor {
+    let o = ren.objects[idx].get_interface();
+    let ks = match o.get_material_at(pt).pbr {
+        Some(ref pbr) => pbr_layers(pbr, o.get_hit_diffuse(pt, hit), n, eye).1,
+        None => o.get_specular(pt),
+    };
+    let t = o.get_material_at(pt).get_transparency();
+    if 0. < t {
+        let mut r = RenderColor::zero();
+        for (frac, mask) in refraction_channels(o.get_material_at(pt), flags) {
+            let fresnel = t * dielectric(eye, n, frac).0;
+            r += mask_channels(RenderColor::new(fresnel, fresnel, fresnel), mask);
         }
+        ks + r
+    } else {
+        ks
+    }
+}
 
-        let obj_t = obj.get_interface().raycast(vi, eye, t, flags);
-        if obj_t < t {
-            t = obj_t;
-            ret_idx = idx;
-        }
+/// Refraction indices to trace through a transparent material, each with the flags of the color
+/// channels it doesn't contribute to. A material whose `frac` differs among channels disperses
+/// light, so each channel not already ignored by `flags` is refracted separately.
+fn refraction_channels(material: &RenderMaterial, flags: u32) -> Vec<(f32, u32)> {
+    let frac = material.frac;
+    if frac.r == frac.g && frac.g == frac.b {
+        return vec![(material.get_refraction_index(), 0)];
+    }
+    [
+        (frac.r, RIGNORE, GIGNORE | BIGNORE),
+        (frac.g, GIGNORE, RIGNORE | BIGNORE),
+        (frac.b, BIGNORE, RIGNORE | GIGNORE),
+    ]
+    .iter()
+    .filter(|(_, channel, _)| 0 == (flags & channel))
+    .map(|&(frac, _, mask)| (frac, mask))
+    .collect()
+}
+
+/// Applies Beer–Lambert absorption to `color` seen along `ray`, which entered object `o` at
+/// `pt`, over the distance to where the ray leaves the object again.
+fn absorb(o: &dyn RenderObjectInterface, pt: &Vec3, ray: &Vec3, color: RenderColor) -> RenderColor {
+    let a = o.get_material_at(pt).absorption;
+    if a.r <= 0. && a.g <= 0. && a.b <= 0. {
+        return color;
+    }
+    let dist = if o.supports_raycast() {
+        o.raycast(pt, ray, f32::INFINITY, OUTONLY)
+    } else {
+        interior_distance(o, pt, ray)
+    };
+    // An open mesh may have no exit, in which case the path length is unknown
+    if dist == f32::INFINITY {
+        return color;
     }
+    RenderColor::new(
+        color.r * (-a.r * dist).exp(),
+        color.g * (-a.g * dist).exp(),
+        color.b * (-a.b * dist).exp(),
+    )
+}
+
+/// Zero the channels ignored by `flags`.
+fn mask_channels(color: RenderColor, flags: u32) -> RenderColor {
+    RenderColor::new(
+        if 0 == (flags & RIGNORE) { color.r } else { 0. },
+        if 0 == (flags & GIGNORE) { color.g } else { 0. },
+        if 0 == (flags & BIGNORE) { color.b } else { 0. },
+    )
+}
+
+/// Random numbers for sampling at a point on a surface, seeded by the position so that the
+/// noise doesn't change between renders or depend on the pixel.
+fn position_rng(pt: &Vec3) -> Rng {
+    Rng::new(
+        pt.x.to_bits() as u64 ^ ((pt.y.to_bits() as u64) << 21) ^ ((pt.z.to_bits() as u64) << 42),
+    )
+}
 
-    (t, ret_idx)
+/// Returns the share of the ambient light reaching `pt` on a surface facing `n`, from 1 where
+/// nothing is around to 0 where nearby surfaces cover the whole hemisphere around `n`.
+fn occlusion(ren: &RenderEnv, pt: &Vec3, n: &Vec3, ao: &AmbientOcclusion) -> f32 {
+    let samples = ao.samples.max(1);
+    if ren.render_mode == RenderMode::RayMarch {
+        // A point stepped away from the surface is as far from any surface as it is from pt
+        // unless something else is nearby. The nearer steps weigh more.
+        let mut occluded = 0.;
+        let mut total = 0.;
+        let mut weight = 1.;
+        for i in 1..=samples {
+            let h = ao.radius * i as f32 / samples as f32;
+            let (dist, _, _) = distance_estimate(ren, &(*pt + *n * h), None, None);
+            occluded += weight * ((h - dist) / h).clamp(0., 1.);
+            total += weight;
+            weight *= 0.8;
+        }
+        1. - occluded / total
+    } else {
+        let mut rng = position_rng(pt);
+        let origin = *pt + *n * ray_offset(pt);
+        let open = (0..samples)
+            .filter(|&i| {
+                let (u, v) = stratified(i, samples, &mut rng);
+                let ray = sample_cosine_hemisphere(n, u, v);
+                ao.radius <= raycast(ren, &origin, &ray, None, 0).0
+            })
+            .count();
+        open as f32 / samples as f32
+    }
 }
 
-fn shading(ren: &RenderEnv, idx: usize, n: &Vec3, pt: &Vec3, eye: &Vec3, nest: i32) -> RenderColor {
+/// Sums up the light reaching `pt` from the light sources. Returns the diffuse irradiance and
+/// the highlight seen along `eye`, by the Phong model or by GGX for a physically based
+/// material.
+fn direct_lighting(
+    ren: &RenderEnv,
+    idx: usize,
+    n: &Vec3,
+    pt: &Vec3,
+    hit: Option<MeshHit>,
+    eye: &Vec3,
+) -> (RenderColor, RenderColor) {
     let o = &ren.objects[idx].get_interface();
+    let pn = o.get_material_at(pt).get_phong_number();
+    // The reflectance of the specular layer doesn't depend on the light, so look up its color
+    // only once
+    let pbr = o
+        .get_material_at(pt)
+        .pbr
+        .map(|pbr| (pbr.f0(o.get_hit_diffuse(pt, hit)), pbr.alpha()));
+    let marching = ren.render_mode == RenderMode::RayMarch;
+    let mut diffuse = RenderColor::zero();
+    let mut k2 = RenderColor::zero();
+    let mut rng = position_rng(pt);
+    for light in &ren.lights {
+        // The ray marcher estimates the penumbra of an area light from a single ray toward
+        // its center instead.
+        let samples = if marching { 1 } else { light.sample_count() };
+        for i in 0..samples {
+            let (s, t) = if samples == 1 {
+                (0.5, 0.5)
+            } else {
+                stratified(i, samples, &mut rng)
+            };
+            let (light_dir, light_dist, light_color) = light.illuminate_from(pt, s, t);
+
+            /* scalar product of light normal and surface normal */
+            let light_incidence = light_dir.dot(n);
+            let ln2 = 2.0 * light_incidence;
+            let reflected_ray_to_light_source = (n * ln2) - light_dir;
 
-    // let mut lv: f32;
-    let (diffuse_intensity, reflected_ray, reflection_intensity) = {
-        /* scalar product of light normal and surface normal */
-        let light_incidence = ren.light.dot(n);
-        let ln2 = 2.0 * light_incidence;
-        let reflected_ray_to_light_source = (n * ln2) - ren.light;
-
-        let eps = std::f32::EPSILON;
-        let pn = o.get_material().get_phong_number();
-        (
-            light_incidence.max(0.),
-            *pt + (ren.light * eps),
-            if 0 != pn {
+            let reflection_intensity = if pbr.is_some() {
+                0.
+            } else if 0 != pn {
                 let reflection_incidence = -reflected_ray_to_light_source.dot(eye);
                 if reflection_incidence > 0.0 {
                     reflection_incidence.powi(pn)
@@ -1041,48 +3714,64 @@ fn shading(ren: &RenderEnv, idx: usize, n: &Vec3, pt: &Vec3, eye: &Vec3, nest: i
                 }
             } else {
                 0.
-            },
-        )
-    };
+            };
 
-    /* shadow trace */
-    let (k1, k2) = {
-        let ray: Vec3 = ren.light;
-        let k1 = 0.2;
-        if ren.use_raymarching {
-            let RaymarchSingleResult {
-                iter, travel_dist, ..
-            } = raymarch_single(ren, &reflected_ray, &ray, Some(&ren.objects[idx]));
-            if FAR_AWAY <= travel_dist
-                || MAX_ITER <= iter
-                || 0.
-                    < ren.objects[idx]
-                        .get_interface()
-                        .get_material()
-                        .get_transparency()
-            {
-                ((k1 + diffuse_intensity).min(1.), reflection_intensity)
-            } else {
-                (k1, 0.)
+            if light_incidence <= 0. && reflection_intensity <= 0. {
+                continue;
             }
-        } else {
-            let (t, i) = raycast(ren, &reflected_ray, &ray, Some(&ren.objects[idx]), 0);
-            if t >= std::f32::INFINITY
-                || 0.
-                    < ren.objects[i]
-                        .get_interface()
-                        .get_material()
-                        .get_transparency()
-            {
-                ((k1 + diffuse_intensity).min(1.), reflection_intensity)
+
+            let size = if marching { light.size() } else { 0. };
+            let light_color =
+                light_color * shadow_transmittance(ren, idx, pt, &light_dir, light_dist, size);
+            let weight = 1. / samples as f32;
+            diffuse += light_color * (light_incidence.max(0.) * weight);
+            if let Some((f0, alpha)) = pbr {
+                // Lights are normalized so that a white Lambertian surface reflects all of their
+                // irradiance, which is pi times the BRDF.
+                let specular = ggx_specular(n, &(*eye * -1.), &light_dir, f0, alpha);
+                k2 += light_color * specular * (std::f32::consts::PI * weight);
             } else {
-                (k1, 0.)
+                k2 += light_color * (reflection_intensity * weight);
             }
         }
+    }
+    (diffuse, k2)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn shading(
+    ren: &RenderEnv,
+    idx: usize,
+    n: &Vec3,
+    pt: &Vec3,
+    hit: Option<MeshHit>,
+    eye: &Vec3,
+    nest: i32,
+    flags: u32,
+) -> RenderColor {
+    let o = &ren.objects[idx].get_interface();
+
+    /* sum up contributions of light sources */
+    let k1 = 0.2;
+    let k1 = if let Some(ref ao) = ren.ambient_occlusion {
+        let facing = if 0. < eye.dot(n) { *n * -1. } else { *n };
+        k1 * occlusion(ren, pt, &facing, ao)
+    } else {
+        k1
     };
+    let (diffuse, k2) = direct_lighting(ren, idx, n, pt, hit, eye);
+    let k1 = RenderColor::new(
+        (k1 + diffuse.r).min(1.),
+        (k1 + diffuse.g).min(1.),
+        (k1 + diffuse.b).min(1.),
+    );
 
     /* face texturing */
-    let kd = o.get_diffuse(pt);
+    let kd = o.get_hit_diffuse(pt, hit);
+    let kd = match o.get_material_at(pt).pbr {
+        Some(ref pbr) => pbr_layers(pbr, kd, n, eye).0,
+        None => kd,
+    };
     // else{
     // 	kd.fred = ren.objects[idx].kdr;
     // 	kd.fgreen = ren.objects[idx].kdg;
@@ -1090,29 +3779,47 @@ fn shading(ren: &RenderEnv, idx: usize, n: &Vec3, pt: &Vec3, eye: &Vec3, nest: i
     // }
 
     /* refraction! */
-    if nest < ren.max_refractions && 0. < o.get_material().get_transparency() {
-        let sp = eye.dot(&n);
-        let f = o.get_material().get_transparency();
-
-        let fc2 = {
-            let frac = o.get_material().get_refraction_index();
-            let reference = sp * (if sp > 0. { frac } else { 1. / frac } - 1.);
-            let mut ray = (*eye + (n * reference)).normalized();
-            let eps = std::f32::EPSILON;
-            let mut pt3 = *pt + (ray * eps);
-            (if ren.use_raymarching {
-                raymarch
-            } else {
-                raytrace
-            })(
-                ren,
-                &mut pt3,
-                &mut ray,
-                nest,
-                Some(&ren.objects[idx]),
-                if sp < 0. { OUTONLY } else { INONLY },
-            )
-        };
+    if nest < ren.max_refractions && 0. < o.get_material_at(pt).get_transparency() {
+        let f = o.get_material_at(pt).get_transparency();
+        let sp = eye.dot(n);
+
+        // The reflected share is traced by the caller along with the specular reflection
+        let mut fc2 = RenderColor::zero();
+        for (frac, mask) in refraction_channels(o.get_material_at(pt), flags) {
+            let (reflectance, refracted) = dielectric(eye, n, frac);
+            if let Some(mut ray) = refracted {
+                let eps = f32::EPSILON;
+                let marching = ren.render_mode == RenderMode::RayMarch;
+                let mut pt3 = if marching && sp < 0. {
+                    inside_surface(*o, pt, n)
+                } else {
+                    *pt + (ray * eps)
+                };
+                let channel_flags = (flags | mask) & (RIGNORE | GIGNORE | BIGNORE);
+                // The tracers move these along with the ray
+                let (entry, entry_ray) = (pt3, ray);
+                let color = (if marching { raymarch } else { raytrace })(
+                    ren,
+                    &mut pt3,
+                    &mut ray,
+                    nest,
+                    // The ray tracer finds the exit point by the flags, while the ray marcher
+                    // needs the object to march its interior or to leave behind.
+                    if marching {
+                        Some(&ren.objects[idx])
+                    } else {
+                        None
+                    },
+                    channel_flags | if sp < 0. { OUTONLY } else { INONLY },
+                );
+                let color = if sp < 0. {
+                    absorb(*o, &entry, &entry_ray, color)
+                } else {
+                    color
+                };
+                fc2 += mask_channels(color, channel_flags) * (1. - reflectance);
+            }
+        }
         /*		t = raycast(ren, &reflectedRay, &ray, &i, &ren->objects[idx], OUTONLY);
         if(t < INFINITY)
         {
@@ -1126,16 +3833,16 @@ fn shading(ren: &RenderEnv, idx: usize, n: &Vec3, pt: &Vec3, eye: &Vec3, nest: i
             ren->bgproc(&ray, &fc2);
         }*/
         RenderColor {
-            r: (kd.r * k1 + k2) * (1. - f) + fc2.r * f,
-            g: (kd.g * k1 + k2) * (1. - f) + fc2.g * f,
-            b: (kd.b * k1 + k2) * (1. - f) + fc2.b * f,
-        }
+            r: (kd.r * k1.r + k2.r) * (1. - f) + fc2.r * f,
+            g: (kd.g * k1.g + k2.g) * (1. - f) + fc2.g * f,
+            b: (kd.b * k1.b + k2.b) * (1. - f) + fc2.b * f,
+        } + o.get_material_at(pt).emission
     } else {
         RenderColor {
-            r: kd.r * k1 + k2,
-            g: kd.g * k1 + k2,
-            b: kd.b * k1 + k2,
-        }
+            r: kd.r * k1.r + k2.r,
+            g: kd.g * k1.g + k2.g,
+            b: kd.b * k1.b + k2.b,
+        } + o.get_material_at(pt).emission
     }
 }
 
@@ -1155,7 +3862,7 @@ fn raytrace(
     let mut ig: Option<&RenderObject> = init_ig;
     loop {
         lev += 1;
-        let (t, idx) = raycast(ren, vi, eye, ig, flags);
+        let (t, idx, hit) = raycast(ren, vi, eye, ig, flags);
         if t < std::f32::INFINITY {
             /*			t -= EPS;*/
 
@@ -1164,13 +3871,13 @@ fn raytrace(
             let pt = (*eye * t) + *vi;
 
             let o = &ren.objects[idx].get_interface();
-            let n = o.get_normal(&pt);
-            let face_color = shading(ren, idx, &n, &pt, eye, lev);
+            let n = o.get_hit_normal(&pt, hit);
+            let face_color = shading(ren, idx, &n, &pt, hit, eye, lev, flags);
             // if idx == 2 {
             //     println!("Hit {}: eye: {:?} normal: {:?} shading: {:?}", idx, eye, n, face_color);
             // }
 
-            let ks = o.get_specular(&pt);
+            let ks = reflectance(ren, idx, &n, &pt, hit, eye, flags);
 
             if 0 == (RIGNORE & flags) {
                 ret_color.r += face_color.r * fcs.r;
@@ -1196,6 +3903,12 @@ fn raytrace(
                 break;
             }
 
+            if 0. < o.get_material_at(&pt).reflection_alpha() && eye.dot(&n) < 0. {
+                let color = glossy_reflection(ren, idx, &pt, &n, eye, lev, flags);
+                ret_color += mask_channels(fcs * color, flags);
+                break;
+            }
+
             *vi = pt;
             let en2 = -2.0 * eye.dot(&n);
             *eye += n * en2;
@@ -1208,9 +3921,14 @@ fn raytrace(
                 flags |= INONLY;
             }
 
-            ig = Some(&ren.objects[idx]);
+            // Reflections inside a transparent object must be able to hit the object itself
+            ig = if 0. < o.get_material_at(&pt).get_transparency() {
+                None
+            } else {
+                Some(&ren.objects[idx])
+            };
         } else {
-            let fc2 = (ren.bgproc)(ren, eye);
+            let fc2 = ren.background.sample(ren, eye);
             ret_color.r += fc2.r * fcs.r;
             ret_color.g += fc2.g * fcs.g;
             ret_color.b += fc2.b * fcs.b;
@@ -1223,29 +3941,46 @@ fn raytrace(
     ret_color
 }
 
-fn distance_estimate(ren: &RenderEnv, vi: &Vec3, ig: Option<&RenderObject>) -> (f32, usize, f32) {
+/// Returns the distance to the closest object, its index and the distance weighted for glow.
+/// The distance to `inside` is negated, so that its surface can be found from its interior.
+fn distance_estimate(
+    ren: &RenderEnv,
+    vi: &Vec3,
+    ig: Option<&RenderObject>,
+    inside: Option<&RenderObject>,
+) -> (f32, usize, f32) {
     let mut closest_dist = std::f32::INFINITY;
     let mut ret_idx = 0;
     let mut glowing_dist = std::f32::INFINITY;
 
-    for (idx, obj) in ren.objects.iter().enumerate() {
-        if let Some(ignore_obj) = ig {
-            if ignore_obj as *const _ == obj as *const _ {
-                continue;
+    ren.bvh.nearest(vi, |idx| {
+        let obj = &ren.objects[idx];
+        let skip = ig.map(|ignore_obj| std::ptr::eq(ignore_obj, obj)) == Some(true);
+        if !skip {
+            let dist = obj.get_interface().distance(vi);
+            let marched_dist = if inside.map(|o| std::ptr::eq(o, obj)) == Some(true) {
+                -dist
+            } else {
+                dist
+            };
+            if marched_dist < closest_dist {
+                closest_dist = marched_dist;
+                ret_idx = idx;
             }
-        }
 
-        let dist = obj.get_interface().distance(vi);
-        if dist < closest_dist {
-            closest_dist = dist;
-            ret_idx = idx;
+            let glow = dist * obj.get_interface().get_material().glow_dist;
+            if 0. < glow && glow < glowing_dist {
+                glowing_dist = glow;
+            }
         }
 
-        let glow = dist * obj.get_interface().get_material().glow_dist;
-        if 0. < glow && glow < glowing_dist {
-            glowing_dist = glow;
+        // Objects farther than this can neither be the closest nor glow stronger
+        if ren.min_glow_dist < f32::INFINITY {
+            closest_dist.max(glowing_dist / ren.min_glow_dist)
+        } else {
+            closest_dist
         }
-    }
+    });
 
     (closest_dist, ret_idx, glowing_dist)
 }
@@ -1254,6 +3989,29 @@ const RAYMARCH_EPS: f32 = 1e-3;
 const FAR_AWAY: f32 = 1e4;
 const MAX_ITER: usize = 10000;
 
+/// Distance from `pt` inside `o` to where the ray leaves it, found by marching its negated
+/// distance function. Returns infinity if the ray never leaves.
+fn interior_distance(o: &dyn RenderObjectInterface, pt: &Vec3, ray: &Vec3) -> f32 {
+    let mut travel_dist = 0.;
+    for _ in 0..MAX_ITER {
+        let dist = -o.distance(&(*pt + *ray * travel_dist));
+        if dist < RAYMARCH_EPS {
+            return travel_dist;
+        }
+        travel_dist += dist;
+        if FAR_AWAY < travel_dist {
+            break;
+        }
+    }
+    f32::INFINITY
+}
+
+/// Moves a point on the surface of `obj` with normal `n` to just inside, far enough that
+/// marching the interior doesn't stop at the surface it started from.
+fn inside_surface(obj: &dyn RenderObjectInterface, pt: &Vec3, n: &Vec3) -> Vec3 {
+    *pt - *n * (obj.distance(pt).max(0.) + 2. * RAYMARCH_EPS)
+}
+
 struct RaymarchSingleResult {
     final_dist: f32,
     idx: usize,
@@ -1261,6 +4019,9 @@ struct RaymarchSingleResult {
     iter: usize,
     travel_dist: f32,
     min_dist: f32,
+    /// The smallest distance to objects divided by the distance traveled to get there, which
+    /// is the tangent of the angle by which the ray missed them.
+    min_ratio: f32,
 }
 
 fn raymarch_single(
@@ -1268,13 +4029,19 @@ fn raymarch_single(
     init_pos: &Vec3,
     eye: &Vec3,
     ig: Option<&RenderObject>,
+    inside: Option<&RenderObject>,
+    ray_length: f32,
 ) -> RaymarchSingleResult {
     let mut iter = 0;
     let mut travel_dist = 0.;
     let mut pos = *init_pos;
     let mut min_dist = std::f32::INFINITY;
+    let mut min_ratio = f32::INFINITY;
     loop {
-        let (dist, idx, glowing_dist) = distance_estimate(ren, &pos, ig);
+        let (dist, idx, glowing_dist) = distance_estimate(ren, &pos, ig, inside);
+        if 0. < travel_dist {
+            min_ratio = min_ratio.min(dist / travel_dist);
+        }
         pos = (*eye * dist) + pos;
         travel_dist += dist;
         iter += 1;
@@ -1283,7 +4050,8 @@ fn raymarch_single(
             min_dist = glowing_dist;
         }
         // println!("raymarch {:?} iter: {} pos: {:?}, dist: {}", eye, iter, pos, dist);
-        if dist < RAYMARCH_EPS || FAR_AWAY < dist || MAX_ITER < iter {
+        if !(RAYMARCH_EPS..=FAR_AWAY).contains(&dist) || MAX_ITER < iter || ray_length < travel_dist
+        {
             return RaymarchSingleResult {
                 final_dist: dist,
                 idx,
@@ -1291,6 +4059,7 @@ fn raymarch_single(
                 iter,
                 travel_dist,
                 min_dist,
+                min_ratio,
             };
         }
     }
@@ -1313,6 +4082,11 @@ fn raymarch(
     /*	bgcolor(eye, pColor);*/
 
     let mut ig: Option<&RenderObject> = init_ig;
+    // A ray that only accepts exits starts inside the given object and marches its interior
+    let mut inside: Option<&RenderObject> = None;
+    if flags & OUTONLY != 0 {
+        inside = ig.take();
+    }
     loop {
         lev += 1;
         let RaymarchSingleResult {
@@ -1322,7 +4096,7 @@ fn raymarch(
             iter,
             min_dist,
             ..
-        } = raymarch_single(ren, &pos, eye, ig);
+        } = raymarch_single(ren, &pos, eye, ig, inside, f32::INFINITY);
         if min_dist < min_min_dist {
             min_min_dist = min_dist;
         }
@@ -1338,12 +4112,12 @@ fn raymarch(
             let o = &ren.objects[idx].get_interface();
             let n = o.get_normal(&pt);
             // let face_color = RenderColor::new(travel_dist / 100. % 1., 0., 0.);
-            let face_color = shading(ren, idx, &n, &pt, eye, lev);
+            let face_color = shading(ren, idx, &n, &pt, None, eye, lev, flags);
             // if idx == 2 {
             // println!("Hit {}: eye: {:?} normal: {:?} shading: {:?}", idx, eye, n, face_color);
             // }
 
-            let ks = o.get_specular(&pt);
+            let ks = reflectance(ren, idx, &n, &pt, None, eye, flags);
 
             if 0 == (RIGNORE & flags) {
                 ret_color.r += face_color.r * fcs.r;
@@ -1365,7 +4139,13 @@ fn raymarch(
                 break;
             }
 
-            if lev >= MAX_REFLECTIONS {
+            if lev >= ren.max_reflections {
+                break;
+            }
+
+            if 0. < o.get_material_at(&pt).reflection_alpha() && eye.dot(&n) < 0. {
+                let color = glossy_reflection(ren, idx, &pt, &n, eye, lev, flags);
+                ret_color += mask_channels(fcs * color, flags);
                 break;
             }
 
@@ -1376,19 +4156,23 @@ fn raymarch(
             if n.dot(&eye) < 0. {
                 flags &= !INONLY;
                 flags |= OUTONLY;
+                // Reflected back into the object, like total internal reflection
+                pos = inside_surface(*o, &pt, &n);
+                inside = Some(&ren.objects[idx]);
+                ig = None;
             } else {
                 flags &= !OUTONLY;
                 flags |= INONLY;
+                inside = None;
+                ig = Some(&ren.objects[idx]);
             }
-
-            ig = Some(&ren.objects[idx]);
         } else {
-            let fc2 = (ren.bgproc)(ren, eye);
+            let fc2 = ren.background.sample(ren, eye);
             ret_color.r += fc2.r * fcs.r;
             ret_color.g += fc2.g * fcs.g;
             ret_color.b += fc2.b * fcs.b;
         }
-        if MAX_REFLECTIONS <= lev {
+        if ren.max_reflections <= lev {
             break;
         }
     }
@@ -1409,3 +4193,1188 @@ fn raymarch(
         ret_color
     }
 }
+
+/// Distance to move the origin of a ray off a surface, relative to the magnitude of the
+/// coordinates, so that it doesn't hit the same surface again by rounding error.
+const RAY_OFFSET: f32 = 1e-4;
+
+fn ray_offset(pt: &Vec3) -> f32 {
+    RAY_OFFSET * (1. + pt.x.abs().max(pt.y.abs()).max(pt.z.abs()))
+}
+
+/// Direction of `ray` mirrored by a surface with normal `n`.
+fn reflect(ray: &Vec3, n: &Vec3) -> Vec3 {
+    *ray - *n * (2. * ray.dot(n))
+}
+
+/// Color reflected along `eye` by the outside of the rough surface of object `idx` at `pt`,
+/// averaged over rays mirrored by microfacets drawn from the GGX distribution of its
+/// roughness. A ray already split by a rough surface traces a single ray at the next one, so
+/// that the number of rays doesn't multiply with every bounce.
+fn glossy_reflection(
+    ren: &RenderEnv,
+    idx: usize,
+    pt: &Vec3,
+    n: &Vec3,
+    eye: &Vec3,
+    lev: i32,
+    flags: u32,
+) -> RenderColor {
+    let marching = ren.render_mode == RenderMode::RayMarch;
+    let material = ren.objects[idx].get_interface().get_material_at(pt);
+    let alpha = material.reflection_alpha();
+    let samples = if flags & GLOSSY != 0 {
+        1
+    } else {
+        ren.glossy_samples.max(1)
+    };
+    // Reflections leaving a transparent object in the ray tracer may hit it again, the same
+    // as mirror reflections
+    let ig = if !marching && 0. < material.get_transparency() {
+        None
+    } else {
+        Some(&ren.objects[idx])
+    };
+    let mut rng = position_rng(pt);
+    let mut sum = RenderColor::zero();
+    for i in 0..samples {
+        let (u, v) = stratified(i, samples, &mut rng);
+        let ray = reflect(eye, &sample_ggx(n, alpha, u, v));
+        // A microfacet tilted too far reflects into the surface, which hides the reflection;
+        // the mirror direction stands in for it
+        let mut ray = if 0. < ray.dot(n) {
+            ray
+        } else {
+            reflect(eye, n)
+        };
+        let mut origin = *pt;
+        let flags = (flags & !OUTONLY) | INONLY | GLOSSY;
+        sum += (if marching { raymarch } else { raytrace })(
+            ren,
+            &mut origin,
+            &mut ray,
+            lev,
+            ig,
+            flags,
+        );
+    }
+    sum * (1. / samples as f32)
+}
+
+/// Estimates the light arriving at `vi` from the direction opposite to `eye` by following a
+/// single random path. Light sources are sampled directly at every diffuse surface, while
+/// emissive objects and the background only contribute when a path happens to hit them.
+fn pathtrace(ren: &RenderEnv, vi: &Vec3, eye: &Vec3, rng: &mut Rng) -> RenderColor {
+    let settings = &ren.path_tracing;
+    let mut radiance = RenderColor::zero();
+    let mut throughput = RenderColor::new(1., 1., 1.);
+    let mut pos = *vi;
+    let mut ray = *eye;
+    let mut bounce = 0;
+    loop {
+        let (t, idx, hit) = raycast(ren, &pos, &ray, None, 0);
+        if t == f32::INFINITY {
+            radiance += throughput * ren.background.sample(ren, &ray);
+            break;
+        }
+        let pt = ray * t + pos;
+        let o = ren.objects[idx].get_interface();
+        let material = o.get_material_at(&pt);
+        let n = o.get_hit_normal(&pt, hit);
+        let inside = 0. < ray.dot(&n);
+        // The normal on the side the path arrives from
+        let facing = if inside { n * -1. } else { n };
+        let a = material.absorption;
+        if inside && (0. < a.r || 0. < a.g || 0. < a.b) {
+            throughput =
+                throughput * RenderColor::new((-a.r * t).exp(), (-a.g * t).exp(), (-a.b * t).exp());
+        }
+        radiance += throughput * material.emission;
+
+        if settings.max_bounces <= bounce {
+            break;
+        }
+        if settings.roulette_depth <= bounce {
+            // Dividing the survivors by the survival probability keeps the estimate unbiased
+            let survival = throughput.r.max(throughput.g).max(throughput.b).min(1.);
+            if survival <= rng.next_f32() {
+                break;
+            }
+            throughput = throughput * (1. / survival);
+        }
+        bounce += 1;
+
+        let offset = ray_offset(&pt);
+        // Each layer of the material is chosen with the probability of its weight, which
+        // cancels the weight itself.
+        if rng.next_f32() < material.get_transparency() {
+            let (reflectance, refracted) = dielectric(&ray, &n, material.get_refraction_index());
+            match refracted {
+                Some(refracted) if reflectance <= rng.next_f32() => {
+                    ray = refracted;
+                    pos = pt - facing * offset;
+                }
+                _ => {
+                    ray = reflect(&ray, &facing);
+                    pos = pt + facing * offset;
+                }
+            }
+            continue;
+        }
+
+        let (diffuse, highlight) = direct_lighting(ren, idx, &facing, &pt, hit, &ray);
+        let (kd, ks) = match material.pbr {
+            Some(ref pbr) => pbr_layers(pbr, o.get_hit_diffuse(&pt, hit), &facing, &ray),
+            None => (o.get_hit_diffuse(&pt, hit), o.get_specular(&pt)),
+        };
+        radiance += throughput * (kd * diffuse + highlight);
+
+        let weight = |c: RenderColor| (c.r + c.g + c.b) / 3.;
+        let (wd, ws) = (weight(kd), weight(ks));
+        if wd + ws <= 0. {
+            break;
+        }
+        if rng.next_f32() * (wd + ws) < wd {
+            // The cosine of Lambert's law cancels with the density of the sampled direction
+            throughput = throughput * kd * ((wd + ws) / wd);
+            ray = sample_cosine_hemisphere(&facing, rng.next_f32(), rng.next_f32());
+        } else if let Some(ref pbr) = material.pbr {
+            // Reflect about a microfacet drawn from the distribution, so that the rays gather
+            // where the lobe is strong
+            let (alpha, v) = (pbr.alpha(), ray * -1.);
+            let h = sample_ggx(&facing, alpha, rng.next_f32(), rng.next_f32());
+            ray = reflect(&ray, &h);
+            let f0 = pbr.f0(o.get_hit_diffuse(&pt, hit));
+            let w = sample_ggx_weight(&facing, &v, &ray, &h, f0, alpha);
+            if w.r <= 0. && w.g <= 0. && w.b <= 0. {
+                break;
+            }
+            throughput = throughput * w * ((wd + ws) / ws);
+        } else {
+            throughput = throughput * ks * ((wd + ws) / ws);
+            let alpha = material.reflection_alpha();
+            if 0. < alpha {
+                let h = sample_ggx(&facing, alpha, rng.next_f32(), rng.next_f32());
+                ray = reflect(&ray, &h);
+                if ray.dot(&facing) <= 0. {
+                    break;
+                }
+            } else {
+                ray = reflect(&ray, &facing);
+            }
+        }
+        pos = pt + facing * offset;
+    }
+    radiance
+}
+
+#[test]
+fn test_dielectric() {
+    let n = Vec3::new(0., 1., 0.);
+    // Normal incidence into glass reflects ((n - 1) / (n + 1))^2
+    let (r, ray) = dielectric(&Vec3::new(0., -1., 0.), &n, 1.5);
+    assert!((r - 0.04).abs() < 1e-6);
+    assert!((ray.unwrap().y + 1.).abs() < 1e-6);
+
+    // Snell's law entering and leaving
+    let eye = Vec3::new(0.6, -0.8, 0.);
+    let (r, ray) = dielectric(&eye, &n, 1.5);
+    let ray = ray.unwrap();
+    assert!((ray.x * 1.5 - eye.x).abs() < 1e-6);
+    assert!(0.04 < r && r < 1.);
+    let (_, back) = dielectric(&Vec3::new(ray.x, -ray.y, 0.), &n, 1.5);
+    assert!((back.unwrap().x - eye.x).abs() < 1e-6);
+
+    // Beyond the critical angle from inside everything is reflected
+    let (r, ray) = dielectric(&Vec3::new(0.8, 0.6, 0.), &n, 1.5);
+    assert!(r == 1. && ray.is_none());
+}
+
+#[test]
+fn test_refraction_channels() {
+    let material = RenderMaterial::new(
+        "glass".to_string(),
+        RenderColor::zero(),
+        RenderColor::zero(),
+        0,
+        1.,
+        1.5,
+    );
+    assert_eq!(refraction_channels(&material, 0), vec![(1.5, 0)]);
+
+    let material = material.frac(RenderColor::new(1.4, 1.5, 1.6));
+    assert_eq!(
+        refraction_channels(&material, 0),
+        vec![
+            (1.4, GIGNORE | BIGNORE),
+            (1.5, RIGNORE | BIGNORE),
+            (1.6, RIGNORE | GIGNORE)
+        ]
+    );
+    // Only the channel still traced is split again inside the object
+    assert_eq!(
+        refraction_channels(&material, GIGNORE | BIGNORE),
+        vec![(1.4, GIGNORE | BIGNORE)]
+    );
+    let masked = mask_channels(RenderColor::new(1., 2., 3.), RIGNORE | BIGNORE);
+    assert_eq!((masked.r, masked.g, masked.b), (0., 2., 0.));
+}
+
+#[test]
+fn test_absorb() {
+    let material = Arc::new(
+        RenderMaterial::new(
+            "glass".to_string(),
+            RenderColor::zero(),
+            RenderColor::zero(),
+            0,
+            1.,
+            1.5,
+        )
+        .absorption(RenderColor::new(0., 0.1, 1.)),
+    );
+    let thin = RenderSphere::new_raw(material.clone(), 1., Vec3::zero());
+    let thick = RenderSphere::new_raw(material, 10., Vec3::zero());
+    let white = RenderColor::new(1., 1., 1.);
+    let ray = Vec3::new(1., 0., 0.);
+    let c = absorb(&thin, &Vec3::new(-1., 0., 0.), &ray, white);
+    assert_eq!(c.r, 1.);
+    assert!((c.g - (-0.2f32).exp()).abs() < 1e-6);
+    let c2 = absorb(&thick, &Vec3::new(-10., 0., 0.), &ray, white);
+    assert!(c2.g < c.g && c2.b < c.b);
+}
+
+#[test]
+fn test_box() {
+    let material = Arc::new(RenderMaterial::new(
+        "box".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let aabb = RenderBox::new_raw(
+        material.clone(),
+        Vec3::new(0., 0., 10.),
+        Vec3::new(1., 2., 3.),
+    );
+    let eye = Vec3::new(0., 0., 1.);
+    assert_eq!(aabb.raycast(&Vec3::zero(), &eye, f32::INFINITY, 0), 7.);
+    assert_eq!(
+        aabb.raycast(&Vec3::zero(), &eye, f32::INFINITY, OUTONLY),
+        13.
+    );
+    assert_eq!(aabb.raycast(&Vec3::zero(), &eye, 5., 0), 5.);
+    let n = aabb.get_normal(&Vec3::new(0.5, 0.5, 7.));
+    assert_eq!((n.x, n.y, n.z), (0., 0., -1.));
+
+    // Signed distance: negative inside, exact outside including corners
+    assert_eq!(aabb.distance(&Vec3::new(0., 0., 10.)), -1.);
+    assert_eq!(aabb.distance(&Vec3::new(0., 0., 5.)), 2.);
+    assert_eq!(aabb.distance(&Vec3::new(4., 6., 10.)), 5.);
+
+    // Rotated a quarter turn around z, the x and y extents swap
+    let obb = RenderBox::new_raw(material, Vec3::zero(), Vec3::new(1., 2., 3.))
+        .rotation(Quat::rotation(std::f32::consts::FRAC_PI_2, 0., 0., 1.));
+    let t = obb.raycast(
+        &Vec3::new(-10., 0., 0.),
+        &Vec3::new(1., 0., 0.),
+        f32::INFINITY,
+        0,
+    );
+    assert!((t - 8.).abs() < 1e-5);
+    assert!((obb.distance(&Vec3::new(1.5, 0., 0.)) + 0.5).abs() < 1e-5);
+    let bounds = obb.bounding_box().unwrap();
+    assert!((bounds.max.x - 2.).abs() < 1e-5 && (bounds.max.y - 1.).abs() < 1e-5);
+}
+
+#[test]
+fn test_quadric_primitives() {
+    let material = Arc::new(RenderMaterial::new(
+        "m".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let (x, z) = (Vec3::new(1., 0., 0.), Vec3::new(0., 0., 1.));
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+    // Cylinder standing on the origin along y, hit from the side and through the caps
+    let axis = Vec3::new(0., 1., 0.);
+    let cylinder = RenderCylinder::new_raw(material.clone(), Vec3::zero(), axis, 1., 4.);
+    let vi = Vec3::new(-5., 2., 0.);
+    assert!(close(cylinder.raycast(&vi, &x, f32::INFINITY, 0), 4.));
+    assert!(close(cylinder.raycast(&vi, &x, f32::INFINITY, OUTONLY), 6.));
+    let down = Vec3::new(0., -1., 0.);
+    assert!(close(
+        cylinder.raycast(&Vec3::new(0.5, 10., 0.), &down, f32::INFINITY, 0),
+        6.
+    ));
+    let n = cylinder.get_normal(&Vec3::new(0.5, 4., 0.));
+    assert!(close(n.y, 1.));
+    assert!(close(cylinder.distance(&Vec3::new(3., 2., 0.)), 2.));
+    assert!(close(cylinder.distance(&Vec3::new(0., 2., 0.)), -1.));
+    assert!(close(cylinder.distance(&Vec3::new(4., 8., 0.)), 5.));
+
+    // Cone with the apex at y = 2, so the radius is 0.5 at y = 1
+    let cone = RenderCone::new_raw(material.clone(), Vec3::zero(), axis, 1., 2.);
+    let vi = Vec3::new(-5., 1., 0.);
+    assert!(close(cone.raycast(&vi, &x, f32::INFINITY, 0), 4.5));
+    assert!(close(cone.raycast(&vi, &x, f32::INFINITY, OUTONLY), 5.5));
+    assert_eq!(
+        cone.raycast(&Vec3::new(-5., 3., 0.), &x, f32::INFINITY, 0),
+        f32::INFINITY
+    );
+    let frustum = cone.clone().top_r(1.);
+    assert!(close(frustum.raycast(&vi, &x, f32::INFINITY, 0), 4.));
+    let n = cone.get_normal(&Vec3::new(0.5, 1., 0.));
+    assert!(close(n.x, 2. / 5f32.sqrt()) && close(n.y, 1. / 5f32.sqrt()));
+    assert!(close(cone.distance(&Vec3::new(0., 3., 0.)), 1.));
+    assert!(close(cone.distance(&Vec3::new(0., -1., 0.)), 1.));
+
+    // Disk facing -z, which has no inside to exit
+    let disk = RenderDisk::new_raw(material.clone(), Vec3::new(0., 0., 5.), z * -1., 1.);
+    assert!(close(disk.raycast(&Vec3::zero(), &z, f32::INFINITY, 0), 5.));
+    assert_eq!(
+        disk.raycast(&Vec3::zero(), &z, f32::INFINITY, OUTONLY),
+        f32::INFINITY
+    );
+    assert_eq!(
+        disk.raycast(&Vec3::new(2., 0., 0.), &z, f32::INFINITY, 0),
+        f32::INFINITY
+    );
+    assert!(close(disk.distance(&Vec3::new(4., 0., 1.)), 5.));
+
+    // Torus in the xz plane, crossed through both sides of the tube
+    let torus = RenderTorus::new_raw(material, Vec3::zero(), axis, 3., 1.);
+    let vi = Vec3::new(-10., 0., 0.);
+    assert!(close(torus.raycast(&vi, &x, f32::INFINITY, 0), 6.));
+    assert!(close(torus.raycast(&vi, &x, f32::INFINITY, OUTONLY), 8.));
+    let inside = Vec3::new(-3., 0., 0.);
+    assert!(close(torus.raycast(&inside, &x, f32::INFINITY, 0), 1.));
+    assert!(close(torus.raycast(&inside, &x, f32::INFINITY, INONLY), 5.));
+    assert_eq!(torus.raycast(&vi, &x, 5., 0), 5.);
+    assert_eq!(
+        torus.raycast(&Vec3::new(0., 5., 0.), &down, f32::INFINITY, 0),
+        f32::INFINITY
+    );
+    let n = torus.get_normal(&Vec3::new(0., 1., 3.));
+    assert!(close(n.y, 1.));
+    assert!(close(torus.distance(&Vec3::zero()), 2.));
+    assert!(close(torus.distance(&Vec3::new(3., 0.5, 0.)), -0.5));
+}
+
+#[test]
+fn test_csg() {
+    let material = Arc::new(RenderMaterial::new(
+        "m".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    // Two overlapping spheres on the x axis, spanning [-2, 2] and [0, 4]
+    let csg = |op| {
+        RenderCsg::new_raw(
+            op,
+            RenderSphere::new(material.clone(), 2., Vec3::zero()),
+            RenderSphere::new(material.clone(), 2., Vec3::new(2., 0., 0.)),
+        )
+    };
+    let (vi, x) = (Vec3::new(-10., 0., 0.), Vec3::new(1., 0., 0.));
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+    let union = csg(CsgOp::Union);
+    assert!(close(union.raycast(&vi, &x, f32::INFINITY, 0), 8.));
+    assert!(close(union.raycast(&vi, &x, f32::INFINITY, OUTONLY), 14.));
+    assert!(close(union.distance(&Vec3::new(5., 0., 0.)), 1.));
+
+    let intersection = csg(CsgOp::Intersection);
+    assert!(close(intersection.raycast(&vi, &x, f32::INFINITY, 0), 10.));
+    assert!(close(
+        intersection.raycast(&vi, &x, f32::INFINITY, OUTONLY),
+        12.
+    ));
+    assert!(close(intersection.distance(&Vec3::new(-1., 0., 0.)), 1.));
+
+    // The carved surface at x = 0 faces out of the remaining solid, into the right sphere
+    let difference = csg(CsgOp::Difference);
+    assert!(close(difference.raycast(&vi, &x, f32::INFINITY, 0), 8.));
+    assert!(close(
+        difference.raycast(&vi, &x, f32::INFINITY, OUTONLY),
+        10.
+    ));
+    assert!(close(difference.get_normal(&Vec3::zero()).x, 1.));
+    assert!(close(difference.distance(&Vec3::new(1., 0., 0.)), 1.));
+    let inside_carved = Vec3::new(1., 0., 0.);
+    assert_eq!(difference.raycast(&inside_carved, &x, 100., 0), 100.);
+    let bounds = difference.bounding_box().unwrap();
+    assert_eq!((bounds.min.x, bounds.max.x), (-2., 2.));
+
+    assert_eq!(
+        combine_intervals(CsgOp::Difference, &[(-f32::INFINITY, 5.)], &[(1., 2.)]),
+        vec![(-f32::INFINITY, 1.), (2., 5.)]
+    );
+}
+
+#[test]
+fn test_csg_material() {
+    let white = Arc::new(RenderMaterial::new(
+        "white".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let glowing_glass = Arc::new(
+        RenderMaterial::new(
+            "glowing_glass".to_string(),
+            RenderColor::zero(),
+            RenderColor::zero(),
+            0,
+            1.,
+            1.,
+        )
+        .emission(RenderColor::new(0., 0., 1.)),
+    );
+    let up = Vec3::new(0., 1., 0.);
+    for mode in [RenderMode::RayTrace, RenderMode::RayMarch] {
+        // An opaque sphere spanning [-2, 2] on the x axis joined with a glowing glass one
+        // spanning [0, 4]
+        let ren = RenderEnv::new(
+            Vec3::zero(),
+            Vec3::zero(),
+            1,
+            1,
+            1.,
+            1.,
+            Background::Solid(RenderColor::zero()),
+        )
+        .objects(vec![
+            RenderFloor::new(white.clone(), Vec3::new(0., -5., 0.), up),
+            RenderCsg::new(
+                CsgOp::Union,
+                RenderSphere::new(white.clone(), 2., Vec3::zero()),
+                RenderSphere::new(glowing_glass.clone(), 2., Vec3::new(2., 0., 0.)),
+            ),
+        ])
+        .render_mode(mode);
+        let csg = ren.objects[1].get_interface();
+        let (left, right) = (Vec3::new(-2., 0., 0.), Vec3::new(4., 0., 0.));
+        assert_eq!(csg.get_material_at(&left).get_transparency(), 0.);
+        assert_eq!(csg.get_material_at(&right).get_transparency(), 1.);
+
+        // Only the right operand glows
+        let x = Vec3::new(1., 0., 0.);
+        let glow = shading(&ren, 1, &x, &right, None, &(x * -1.), 0, 0);
+        assert!(1. <= glow.b, "{:?}", glow);
+        let unlit = shading(&ren, 1, &(x * -1.), &left, None, &x, 0, 0);
+        assert!(unlit.b < 1., "{:?}", unlit);
+
+        // Light passes through the right operand alone, but not through the left one
+        let c = shadow_transmittance(&ren, 0, &Vec3::new(3.5, -5., 0.), &up, 10., 0.);
+        assert!(0.9 < c.r, "{:?}", c);
+        let c = shadow_transmittance(&ren, 0, &Vec3::new(-1., -5., 0.), &up, 10., 0.);
+        assert_eq!(c.r, 0.);
+    }
+}
+
+#[test]
+fn test_instance() {
+    let material = Arc::new(RenderMaterial::new(
+        "m".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let sphere = Arc::new(RenderSphere::new(material.clone(), 1., Vec3::zero()));
+    // Stretched twice as wide along x and moved 10 along z
+    let transform = Transform::default()
+        .translation(Vec3::new(0., 0., 10.))
+        .scale(Vec3::new(2., 1., 1.));
+    let instance = RenderInstance::new_raw(sphere.clone(), transform).geometry("ball");
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+    let z = Vec3::new(0., 0., 1.);
+    assert!(close(
+        instance.raycast(&Vec3::zero(), &z, f32::INFINITY, 0),
+        9.
+    ));
+    let (vi, x) = (Vec3::new(-10., 0., 10.), Vec3::new(1., 0., 0.));
+    assert!(close(instance.raycast(&vi, &x, f32::INFINITY, 0), 8.));
+    assert!(close(
+        instance.raycast(&vi, &x, f32::INFINITY, OUTONLY),
+        12.
+    ));
+    assert_eq!(instance.raycast(&vi, &x, 5., 0), 5.);
+    assert!(close(instance.get_normal(&Vec3::new(-2., 0., 10.)).x, -1.));
+    let n = instance.get_normal(&Vec3::new(2f32.sqrt(), 0.5f32.sqrt(), 10.));
+    assert!(close(n.x * 2., n.y));
+    let d = instance.distance(&Vec3::new(5., 0., 10.));
+    assert!(0. < d && d <= 3.);
+    let bounds = instance.bounding_box().unwrap();
+    assert_eq!((bounds.min.x, bounds.max.z), (-2., 11.));
+
+    // Shared geometries survive serialization as references
+    let mut geometries = HashMap::new();
+    geometries.insert("ball".to_string(), sphere);
+    let ren = RenderEnv::new(
+        Vec3::zero(),
+        Vec3::zero(),
+        1,
+        1,
+        1.,
+        1.,
+        Background::Solid(RenderColor::zero()),
+    )
+    .geometries(geometries)
+    .objects(vec![RenderObject::Instance(instance)]);
+    let serialized = ren.serialize().unwrap();
+    let mut ren2 = RenderEnv::new(
+        Vec3::zero(),
+        Vec3::zero(),
+        1,
+        1,
+        1.,
+        1.,
+        Background::Solid(RenderColor::zero()),
+    );
+    ren2.deserialize(&serialized).ok().unwrap();
+    assert!(ren2.geometries.contains_key("ball"));
+    assert_eq!(ren2.serialize().unwrap(), serialized);
+    let t = ren2.objects[0]
+        .get_interface()
+        .raycast(&vi, &x, f32::INFINITY, 0);
+    assert!(close(t, 8.));
+
+    // The triangle of a mesh hit through an instance is passed on to find its normal, which
+    // tells apart the two sides of a sheet in the same place
+    let dir = std::env::temp_dir().join("ray-rust-test-instance");
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("sheet.obj");
+    std::fs::write(&file, "v -1 -1 0\nv 1 -1 0\nv 0 1 0\nf 1 2 3\nf 3 2 1\n").unwrap();
+    let sheet = RenderMesh::new(material, file.to_str().unwrap(), Transform::identity()).unwrap();
+    let instance = RenderInstance::new_raw(Arc::new(sheet), transform);
+    let (t, hit) = instance.raycast_hit(&Vec3::zero(), &z, f32::INFINITY, OUTONLY);
+    assert!(close(t, 10.));
+    assert!(instance.get_hit_normal(&(z * t), hit).z < 0.);
+}
+
+#[test]
+fn test_sdf_objects() {
+    let material = Arc::new(RenderMaterial::new(
+        "m".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let capsule = RenderSdf::new(
+        material.clone(),
+        Vec3::new(0., 0., 10.),
+        SdfShape::Capsule {
+            a: Vec3::new(-2., 0., 0.),
+            b: Vec3::new(2., 0., 0.),
+            r: 1.,
+        },
+    );
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+    let interface = capsule.get_interface();
+    assert!(close(interface.distance(&Vec3::new(0., 3., 10.)), 2.));
+    assert!(close(interface.get_normal(&Vec3::new(0., 1., 10.)).y, 1.));
+    assert!(!interface.supports_raycast());
+
+    // Two spheres just touching melt into a neck between them
+    let union = RenderSmoothUnion::new_raw(
+        1.,
+        RenderSphere::new(material.clone(), 1., Vec3::new(-1., 0., 0.)),
+        RenderSphere::new(material.clone(), 1., Vec3::new(1., 0., 0.)),
+    );
+    assert!(union.distance(&Vec3::new(0., 1., 0.)) < 2f32.sqrt() - 1.);
+    assert!(close(union.distance(&Vec3::new(-4., 0., 0.)), 2.));
+    assert!(close(union.get_normal(&Vec3::new(-2., 0., 0.)).x, -1.));
+    let bounds = union.bounding_box().unwrap();
+    assert_eq!((bounds.min.x, bounds.max.y), (-2.25, 1.25));
+
+    // The ray tracer refuses objects it can't intersect, even nested in a CSG tree
+    let csg = RenderCsg::new(
+        CsgOp::Union,
+        RenderSphere::new(material.clone(), 1., Vec3::zero()),
+        capsule,
+    );
+    let mut ren = RenderEnv::new(
+        Vec3::zero(),
+        Vec3::zero(),
+        2,
+        2,
+        1.,
+        1.,
+        Background::Solid(RenderColor::zero()),
+    )
+    .objects(vec![csg]);
+    assert!(render(&ren, &mut |_, _, _| (), 1).is_err());
+    ren.render_mode = RenderMode::RayMarch;
+    assert!(render(&ren, &mut |_, _, _| (), 1).is_ok());
+}
+
+#[test]
+fn test_interior_marching() {
+    let material = Arc::new(RenderMaterial::new(
+        "m".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-2;
+
+    // Distances are negative inside
+    let sphere = RenderSphere::new_raw(material.clone(), 2., Vec3::zero());
+    assert!(close(sphere.distance(&Vec3::new(0., 1., 0.)), -1.));
+    let floor = RenderFloor::new_raw(material.clone(), Vec3::zero(), Vec3::new(0., 1., 0.));
+    assert!(close(floor.distance(&Vec3::new(0., -3., 0.)), -3.));
+
+    // A point pushed just inside the surface marches through to the other side
+    let n = sphere.get_normal(&Vec3::new(-2., 0., 0.));
+    let start = inside_surface(&sphere, &Vec3::new(-2., 0., 0.), &n);
+    assert!(sphere.distance(&start) < -RAYMARCH_EPS);
+    let dist = interior_distance(&sphere, &start, &Vec3::new(1., 0., 0.));
+    assert!(close(start.x + dist, 2.));
+
+    let capsule = RenderSdf::new_raw(
+        material,
+        Vec3::zero(),
+        SdfShape::Capsule {
+            a: Vec3::new(-2., 0., 0.),
+            b: Vec3::new(2., 0., 0.),
+            r: 1.,
+        },
+    );
+    assert!(close(
+        interior_distance(&capsule, &Vec3::zero(), &Vec3::new(1., 0., 0.)),
+        3.
+    ));
+}
+
+#[test]
+fn test_serialize_roundtrip() {
+    fn render_pixels(ren: &RenderEnv) -> Vec<RenderColor> {
+        let mut data = vec![RenderColor::zero(); (ren.xres * ren.yres) as usize];
+        render(
+            ren,
+            &mut |x, y, c| data[(x + y * ren.xres) as usize] = *c,
+            1,
+        )
+        .unwrap();
+        data
+    }
+
+    let mirror = Arc::new(RenderMaterial::new(
+        "mirror".to_string(),
+        RenderColor::new(0.1, 0.1, 0.1),
+        RenderColor::new(0.8, 0.8, 0.8),
+        24,
+        0.,
+        0.,
+    ));
+    let glass = Arc::new(
+        RenderMaterial::new(
+            "glass".to_string(),
+            RenderColor::new(0., 0., 0.),
+            RenderColor::new(0., 0., 0.),
+            0,
+            1.,
+            1.5,
+        )
+        .glow_dist(3.),
+    );
+    let floor = Arc::new(
+        RenderMaterial::new(
+            "floor".to_string(),
+            RenderColor::new(1., 1., 0.),
+            RenderColor::new(0., 0., 0.),
+            0,
+            0.,
+            0.,
+        )
+        .pattern(RenderPattern::Checkerboard)
+        .pattern_scale(50.),
+    );
+    let objects = vec![
+        RenderFloor::new(floor, Vec3::new(0., -100., 0.), Vec3::new(0., 1., 0.)),
+        RenderSphere::new(mirror, 50., Vec3::new(-60., -50., 200.)),
+        RenderSphere::new(glass, 40., Vec3::new(60., -60., 150.)),
+    ];
+
+    for &render_mode in &[
+        RenderMode::RayTrace,
+        RenderMode::RayMarch,
+        RenderMode::PathTrace,
+    ] {
+        let mut ren = RenderEnv::new(
+            Vec3::new(0., -20., -100.),
+            Vec3::new(0.1, -std::f32::consts::PI / 2., -std::f32::consts::PI / 2.),
+            24,
+            16,
+            1.,
+            16. / 24.,
+            Background::Gradient {
+                bottom: RenderColor::new(0.5, 0.2, 0.),
+                top: RenderColor::new(0., 0.2, 0.8),
+            },
+        )
+        .objects(objects.clone())
+        .lights(vec![
+            RenderLight::directional(Vec3::new(1., 2., -1.)),
+            RenderLight::point(Vec3::new(0., 50., 100.), 100.)
+                .color(RenderColor::new(1., 0.5, 0.5))
+                .intensity(2.),
+        ])
+        .render_mode(render_mode)
+        .glow_effect(Some(0.5))
+        .antialiasing(AntiAliasing::new(
+            4,
+            crate::sampling::SamplePattern::Jittered,
+            crate::sampling::PixelFilter::Gaussian,
+        ));
+        ren.max_reflections = 5;
+        ren.max_refractions = 4;
+        ren.camera.aperture = 2.;
+        ren.camera.focus_distance = 150.;
+
+        let expected = render_pixels(&ren);
+        let serialized = ren.serialize().unwrap();
+
+        let mut deserialized = RenderEnv::new(
+            Vec3::zero(),
+            Vec3::zero(),
+            24,
+            16,
+            1.,
+            16. / 24.,
+            Background::Sky,
+        );
+        assert!(deserialized.deserialize(&serialized).is_ok());
+        assert_eq!(deserialized.serialize().unwrap(), serialized);
+
+        for (a, b) in expected.iter().zip(render_pixels(&deserialized).iter()) {
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+        }
+    }
+}
+
+#[test]
+fn test_pathtrace() {
+    let material = |name: &str, diffuse: f32| {
+        Arc::new(RenderMaterial::new(
+            name.to_string(),
+            RenderColor::new(diffuse, diffuse, diffuse),
+            RenderColor::zero(),
+            0,
+            0.,
+            0.,
+        ))
+    };
+    let mut ren = RenderEnv::new(
+        Vec3::new(0., 10., 0.),
+        Vec3::new(0., 0., -std::f32::consts::PI / 2.),
+        4,
+        4,
+        0.5,
+        0.5,
+        Background::Solid(RenderColor::new(1., 1., 1.)),
+    )
+    .objects(vec![RenderFloor::new(
+        material("gray", 0.5),
+        Vec3::zero(),
+        Vec3::new(0., 1., 0.),
+    )])
+    .lights(vec![])
+    .render_mode(RenderMode::PathTrace);
+    let mut rng = Rng::new(0);
+
+    // Every path reflects off the floor once into the uniform sky, so there is no noise
+    let down = Vec3::new(0.1, -1., 0.2).normalized();
+    for _ in 0..10 {
+        let c = pathtrace(&ren, &ren.camera.position, &down, &mut rng);
+        assert!((c.r - 0.5).abs() < 1e-6 && (c.g - 0.5).abs() < 1e-6);
+    }
+
+    // Emissive objects are visible by themselves and light up their surroundings
+    let lamp = Arc::new(
+        RenderMaterial::new(
+            "lamp".to_string(),
+            RenderColor::zero(),
+            RenderColor::zero(),
+            0,
+            0.,
+            0.,
+        )
+        .emission(RenderColor::new(4., 2., 1.)),
+    );
+    ren.background = Background::Solid(RenderColor::zero());
+    ren.push_object(RenderSphere::new(lamp, 5., Vec3::new(0., 20., 0.)));
+    let up = Vec3::new(0., 1., 0.);
+    let c = pathtrace(&ren, &ren.camera.position, &up, &mut rng);
+    assert_eq!((c.r, c.g, c.b), (4., 2., 1.));
+    let lit = (0..1000)
+        .map(|_| pathtrace(&ren, &ren.camera.position, &down, &mut rng).r)
+        .sum::<f32>();
+    assert!(0. < lit);
+
+    ren.path_tracing.passes = 3;
+    let mut passes = vec![];
+    let framebuffer = render_progressive(&ren, &mut |pass, _| passes.push(pass), 1).unwrap();
+    assert_eq!(passes, vec![1, 2, 3]);
+    assert_eq!((framebuffer.width(), framebuffer.height()), (4, 4));
+
+    // Scene files from before render modes select ray marching by a flag
+    let serialized = ren.serialize().unwrap();
+    assert!(serialized.contains("render_mode: PathTrace"));
+    let legacy = serialized.replace("render_mode: PathTrace", "use_raymarching: true");
+    assert!(ren.deserialize(&legacy).is_ok());
+    assert_eq!(ren.render_mode, RenderMode::RayMarch);
+}
+
+#[test]
+fn test_soft_shadow() {
+    let material = Arc::new(RenderMaterial::new(
+        "white".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let floor = RenderFloor::new(material.clone(), Vec3::zero(), Vec3::new(0., 1., 0.));
+    let occluder = RenderSphere::new(material, 1.5, Vec3::new(0., 5., 0.));
+    let scene = |objects: Vec<RenderObject>, mode: RenderMode| {
+        RenderEnv::new(
+            Vec3::zero(),
+            Vec3::zero(),
+            1,
+            1,
+            1.,
+            1.,
+            Background::Solid(RenderColor::zero()),
+        )
+        .objects(objects)
+        .lights(vec![RenderLight::sphere(Vec3::new(0., 10., 0.), 1e5, 2.)])
+        .render_mode(mode)
+    };
+    let n = Vec3::new(0., 1., 0.);
+    let eye = Vec3::new(0., -1., 0.);
+    for mode in [RenderMode::RayTrace, RenderMode::RayMarch] {
+        let shadowed = scene(vec![floor.clone(), occluder.clone()], mode);
+        let open = scene(vec![floor.clone()], mode);
+        let visibility = |x: f32| {
+            let pt = Vec3::new(x, 0., 0.);
+            direct_lighting(&shadowed, 0, &n, &pt, None, &eye).0.r
+                / direct_lighting(&open, 0, &n, &pt, None, &eye).0.r
+        };
+        assert!(visibility(0.) < 0.05);
+        assert!(0.99 < visibility(10.));
+        // Between the umbra and the lit floor, part of the light is covered
+        assert!((1..20).any(|i| {
+            let v = visibility(i as f32 * 0.25);
+            0.1 < v && v < 0.9
+        }));
+    }
+}
+
+#[test]
+fn test_shadow_transmittance() {
+    let floor = Arc::new(RenderMaterial::new(
+        "floor".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let red_glass = Arc::new(
+        RenderMaterial::new(
+            "red_glass".to_string(),
+            RenderColor::zero(),
+            RenderColor::zero(),
+            0,
+            1.,
+            1.5,
+        )
+        .absorption(RenderColor::new(0., 0.5, 0.5)),
+    );
+    let pt = Vec3::zero();
+    let up = Vec3::new(0., 1., 0.);
+    for mode in [RenderMode::RayTrace, RenderMode::RayMarch] {
+        let ren = RenderEnv::new(
+            Vec3::zero(),
+            Vec3::zero(),
+            1,
+            1,
+            1.,
+            1.,
+            Background::Solid(RenderColor::zero()),
+        )
+        .objects(vec![
+            RenderFloor::new(floor.clone(), Vec3::zero(), up),
+            RenderSphere::new(red_glass.clone(), 1., Vec3::new(0., 5., 0.)),
+            RenderSphere::new(floor.clone(), 1., Vec3::new(5., 5., 0.)),
+        ])
+        .render_mode(mode);
+
+        // Light through the sphere loses 4% at each surface and is absorbed along its diameter
+        let c = shadow_transmittance(&ren, 0, &pt, &up, 10., 0.);
+        assert!((c.r - 0.96 * 0.96).abs() < 1e-2, "{:?}", c);
+        assert!((c.g - 0.96 * 0.96 * (-1f32).exp()).abs() < 1e-2, "{:?}", c);
+        assert_eq!(c.g, c.b);
+
+        // An opaque sphere blocks the light, while nothing lies in front of a light before it
+        let side = Vec3::new(5., 0., 0.);
+        assert_eq!(shadow_transmittance(&ren, 0, &side, &up, 10., 0.).r, 0.);
+        assert_eq!(shadow_transmittance(&ren, 0, &side, &up, 2., 0.).r, 1.);
+    }
+}
+
+#[test]
+fn test_ambient_occlusion() {
+    let material = Arc::new(RenderMaterial::new(
+        "white".to_string(),
+        RenderColor::new(1., 1., 1.),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    let ao = AmbientOcclusion {
+        samples: 64,
+        radius: 10.,
+    };
+    let n = Vec3::new(0., 1., 0.);
+    for mode in [RenderMode::RayTrace, RenderMode::RayMarch] {
+        // A ball resting on the floor, seen from above
+        let ren = RenderEnv::new(
+            Vec3::new(0., 20., 0.),
+            Vec3::new(-std::f32::consts::FRAC_PI_2, 0., 0.),
+            4,
+            3,
+            1.,
+            1.,
+            Background::Solid(RenderColor::zero()),
+        )
+        .objects(vec![
+            RenderFloor::new(material.clone(), Vec3::zero(), n),
+            RenderSphere::new(material.clone(), 2., Vec3::new(0., 2., 0.)),
+        ])
+        .render_mode(mode)
+        .ambient_occlusion(Some(ao));
+        let contact = occlusion(&ren, &Vec3::new(2.5, 0., 0.), &n, &ao);
+        let open = occlusion(&ren, &Vec3::new(50., 0., 0.), &n, &ao);
+        assert!(contact < 0.9, "{:?}: {}", mode, contact);
+        assert_eq!(open, 1.);
+        // The occluder is out of reach with a smaller radius
+        let short = AmbientOcclusion { radius: 0.1, ..ao };
+        assert_eq!(occlusion(&ren, &Vec3::new(2.5, 0., 0.), &n, &short), 1.);
+
+        let framebuffer = render_ao_framebuffer(&ren, 1).unwrap();
+        assert_eq!((framebuffer.width(), framebuffer.height()), (4, 3));
+    }
+}
+
+#[test]
+fn test_pbr_material() {
+    let n = Vec3::new(0., 1., 0.);
+    let eye = Vec3::new(0., -1., 0.);
+    let base = RenderColor::new(1., 0.5, 0.);
+    let metal = PbrMaterial {
+        base_color: base,
+        metallic: 1.,
+        roughness: 0.3,
+        ior: 1.5,
+    };
+    let (kd, ks) = pbr_layers(&metal, base, &n, &eye);
+    assert_eq!((kd.r, kd.g, kd.b), (0., 0., 0.));
+    assert_eq!((ks.r, ks.g, ks.b), (1., 0.5, 0.));
+    let plastic = PbrMaterial {
+        metallic: 0.,
+        ..metal
+    };
+    let (kd, ks) = pbr_layers(&plastic, base, &n, &eye);
+    assert!((kd.r - 0.96).abs() < 1e-6 && (ks.g - 0.04).abs() < 1e-6);
+
+    // The highlight is the brightest where the light is mirrored toward the eye and gets
+    // narrower on smoother surfaces
+    let highlight = |roughness: f32, x: f32| {
+        let material = Arc::new(
+            RenderMaterial::new(
+                "pbr".to_string(),
+                RenderColor::zero(),
+                RenderColor::zero(),
+                0,
+                0.,
+                0.,
+            )
+            .pbr(PbrMaterial { roughness, ..metal }),
+        );
+        let ren = RenderEnv::new(
+            Vec3::zero(),
+            Vec3::zero(),
+            1,
+            1,
+            1.,
+            1.,
+            Background::Solid(RenderColor::zero()),
+        )
+        .objects(vec![RenderFloor::new(material, Vec3::zero(), n)])
+        .lights(vec![RenderLight::directional(Vec3::new(x, 1., 0.))]);
+        direct_lighting(&ren, 0, &n, &Vec3::zero(), None, &eye).1.r
+    };
+    assert!(highlight(0.3, 0.5) < highlight(0.3, 0.));
+    assert!(highlight(0.6, 0.) < highlight(0.3, 0.));
+    assert!(highlight(0.3, 0.5) < highlight(0.6, 0.5));
+
+    // Materials written before the physically based option still load
+    let material = RenderMaterial::new(
+        "pbr".to_string(),
+        RenderColor::zero(),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    );
+    let mut serial = serde_yaml::to_value(material.serialize()).unwrap();
+    serial.as_mapping_mut().unwrap().remove(&"pbr".into());
+    let legacy: RenderMaterialSerial = serde_yaml::from_value(serial).unwrap();
+    assert!(matches!(RenderMaterial::deserialize(&legacy), Ok(m) if m.pbr.is_none()));
+    let serial = serde_yaml::to_string(&material.pbr(plastic).serialize()).unwrap();
+    let serial: RenderMaterialSerial = serde_yaml::from_str(&serial).unwrap();
+    let pbr = RenderMaterial::deserialize(&serial)
+        .ok()
+        .and_then(|m| m.pbr)
+        .unwrap();
+    assert_eq!((pbr.metallic, pbr.base_color.g), (0., 0.5));
+}
+
+#[test]
+fn test_glossy_reflection() {
+    let lamp = Arc::new(
+        RenderMaterial::new(
+            "lamp".to_string(),
+            RenderColor::zero(),
+            RenderColor::zero(),
+            0,
+            0.,
+            0.,
+        )
+        .emission(RenderColor::new(1., 1., 1.)),
+    );
+    let mirror = |roughness: f32| {
+        Arc::new(
+            RenderMaterial::new(
+                "mirror".to_string(),
+                RenderColor::zero(),
+                RenderColor::new(1., 1., 1.),
+                0,
+                0.,
+                0.,
+            )
+            .roughness(roughness),
+        )
+    };
+    for mode in [RenderMode::RayTrace, RenderMode::RayMarch] {
+        // A glowing ball above a mirror floor, seen straight down at the floor
+        let reflected = |roughness: f32, x: f32| {
+            let mut ren = RenderEnv::new(
+                Vec3::zero(),
+                Vec3::zero(),
+                1,
+                1,
+                1.,
+                1.,
+                Background::Solid(RenderColor::zero()),
+            )
+            // The first object never reflects, so the floor comes after
+            .objects(vec![
+                RenderSphere::new(lamp.clone(), 2., Vec3::new(0., 10., 0.)),
+                RenderFloor::new(mirror(roughness), Vec3::zero(), Vec3::new(0., 1., 0.)),
+            ])
+            .render_mode(mode);
+            ren.glossy_samples = 64;
+            let tracer = if mode == RenderMode::RayMarch {
+                raymarch
+            } else {
+                raytrace
+            };
+            tracer(
+                &ren,
+                &mut Vec3::new(x, 1., 0.),
+                &mut Vec3::new(0., -1., 0.),
+                0,
+                None,
+                0,
+            )
+            .r
+        };
+        assert!((reflected(0., 0.) - 1.).abs() < 1e-3);
+        assert_eq!(reflected(0., 2.5), 0.);
+        // A rough floor blurs the ball, so that part of it is seen beside its mirror image and
+        // part of the rays miss it
+        let blurred = reflected(0.5, 0.);
+        assert!(0.05 < blurred && blurred < 0.99, "{:?}: {}", mode, blurred);
+        assert!(0. < reflected(0.5, 2.5));
+    }
+}
+
+#[test]
+fn test_mesh_hit() {
+    let material = Arc::new(RenderMaterial::new(
+        "plate".to_string(),
+        RenderColor::zero(),
+        RenderColor::zero(),
+        0,
+        0.,
+        0.,
+    ));
+    // A sheet without thickness whose two sides are faces in the same place, which the nearest
+    // point can't tell apart
+    let dir = std::env::temp_dir().join("ray-rust-test-mesh-hit");
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("sheet.obj");
+    std::fs::write(&file, "v -1 -1 0\nv 1 -1 0\nv 0 1 0\nf 1 2 3\nf 3 2 1\n").unwrap();
+    let mesh = RenderMesh::new(material, file.to_str().unwrap(), Transform::identity()).unwrap();
+    let interface = mesh.get_interface();
+    let (vi, eye) = (Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.));
+    // Only the side facing the ray is hit
+    let (t, hit) = interface.raycast_hit(&vi, &eye, f32::INFINITY, OUTONLY);
+    assert_eq!(t, 5.);
+    assert!(interface.get_hit_normal(&(eye * t + vi), hit).z < 0.);
+
+    // Mesh paths in a scene are relative to the scene file
+    let ren = RenderEnv::new(
+        Vec3::zero(),
+        Vec3::zero(),
+        1,
+        1,
+        1.,
+        1.,
+        Background::Solid(RenderColor::zero()),
+    )
+    .objects(vec![mesh]);
+    let scene = ren
+        .serialize()
+        .unwrap()
+        .replace(file.to_str().unwrap(), "sheet.obj");
+    let mut loaded = ren.clone();
+    assert!(loaded.deserialize(&scene).is_err());
+    loaded.scene_dir = dir;
+    assert!(loaded.deserialize(&scene).is_ok());
+    assert_eq!(loaded.objects.len(), 1);
+}
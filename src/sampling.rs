@@ -0,0 +1,272 @@
+use crate::vec3::Vec3;
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+/// Small xorshift pseudo random number generator. Seeded per pixel so that images don't
+/// depend on how the work is split among threads.
+#[derive(Clone, Copy)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Scramble the seed with splitmix64 so that neighboring seeds give unrelated sequences
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SamplePattern {
+    /// Regular grid of subpixel positions
+    Grid,
+    /// One random position in each grid cell
+    Jittered,
+    /// Independent random positions
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PixelFilter {
+    /// Equal weights within the pixel
+    Box,
+    /// Linear falloff over a radius of one pixel
+    Tent,
+    /// Gaussian falloff over a radius of one and a half pixels
+    Gaussian,
+}
+
+impl PixelFilter {
+    /// Half width of the filter footprint in pixels.
+    pub fn radius(&self) -> f32 {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.,
+            PixelFilter::Gaussian => 1.5,
+        }
+    }
+
+    /// Weight of a sample at offset (dx, dy) from the pixel center.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        match self {
+            PixelFilter::Box => 1.,
+            PixelFilter::Tent => (1. - dx.abs()).max(0.) * (1. - dy.abs()).max(0.),
+            PixelFilter::Gaussian => (-2. * (dx * dx + dy * dy)).exp(),
+        }
+    }
+}
+
+/// Supersampling anti-aliasing settings. One sample at the pixel center is the same as no
+/// anti-aliasing at all.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AntiAliasing {
+    /// Samples per pixel
+    pub samples: u32,
+    pub pattern: SamplePattern,
+    pub filter: PixelFilter,
+}
+
+impl Default for AntiAliasing {
+    fn default() -> Self {
+        Self {
+            samples: 1,
+            pattern: SamplePattern::Grid,
+            filter: PixelFilter::Box,
+        }
+    }
+}
+
+impl AntiAliasing {
+    #[allow(dead_code)]
+    pub fn new(samples: u32, pattern: SamplePattern, filter: PixelFilter) -> Self {
+        Self {
+            samples,
+            pattern,
+            filter,
+        }
+    }
+
+    /// Returns the offset of the i-th sample from the pixel center in pixels, and its
+    /// filter weight.
+    pub fn sample(&self, i: u32, rng: &mut Rng) -> (f32, f32, f32) {
+        let samples = self.samples.max(1);
+        let (u, v) = match self.pattern {
+            SamplePattern::Grid => grid_point(i, samples, 0.5, 0.5),
+            SamplePattern::Jittered => stratified(i, samples, rng),
+            SamplePattern::Random => (rng.next_f32(), rng.next_f32()),
+        };
+        let radius = self.filter.radius();
+        let (dx, dy) = ((2. * u - 1.) * radius, (2. * v - 1.) * radius);
+        (dx, dy, self.filter.weight(dx, dy))
+    }
+}
+
+/// Returns the point at (`du`, `dv`) in [0, 1) within the cell of the i-th of `samples` points
+/// in a grid over the unit square. The rows are as long as those of a square grid, except that
+/// the last one may be shorter, and the row heights are proportional to their lengths so that
+/// all the cells have the same area.
+fn grid_point(i: u32, samples: u32, du: f32, dv: f32) -> (f32, f32) {
+    let cols = (samples as f32).sqrt().ceil() as u32;
+    let row = i / cols;
+    let row_len = cols.min(samples - row * cols);
+    (
+        ((i % cols) as f32 + du) / row_len as f32,
+        ((row * cols) as f32 + dv * row_len as f32) / samples as f32,
+    )
+}
+
+/// Returns the i-th of `samples` points in the unit square, placed randomly within its own cell
+/// of a grid so that the points cover the square evenly.
+pub fn stratified(i: u32, samples: u32, rng: &mut Rng) -> (f32, f32) {
+    let (du, dv) = (rng.next_f32(), rng.next_f32());
+    grid_point(i, samples.max(1), du, dv)
+}
+
+/// Maps a pair of uniform numbers in [0, 1) to a uniformly distributed point on the unit disk
+/// with Shirley's concentric mapping, which keeps stratified samples stratified.
+pub fn sample_disk(u: f32, v: f32) -> (f32, f32) {
+    let (a, b) = (2. * u - 1., 2. * v - 1.);
+    if a == 0. && b == 0. {
+        return (0., 0.);
+    }
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, FRAC_PI_2 - FRAC_PI_4 * (a / b))
+    };
+    (r * phi.cos(), r * phi.sin())
+}
+
+/// Returns two unit vectors perpendicular to the unit vector `n` and to each other.
+pub fn tangent_frame(n: &Vec3) -> (Vec3, Vec3) {
+    // Cross with the axis least aligned with n to stay away from degenerate products
+    let other = if n.x.abs() < 0.5 {
+        Vec3::new(1., 0., 0.)
+    } else {
+        Vec3::new(0., 1., 0.)
+    };
+    let tangent = n.cross(&other).normalized();
+    (tangent, n.cross(&tangent))
+}
+
+/// Maps a pair of uniform numbers in [0, 1) to a direction in the hemisphere around the unit
+/// vector `n`, distributed with a density proportional to the cosine from `n`.
+pub fn sample_cosine_hemisphere(n: &Vec3, u: f32, v: f32) -> Vec3 {
+    // Projecting uniform points on the disk up to the hemisphere gives the cosine density
+    let (x, y) = sample_disk(u, v);
+    let z = (1. - x * x - y * y).max(0.).sqrt();
+    let (tangent, bitangent) = tangent_frame(n);
+    (tangent * x + bitangent * y + *n * z).normalized()
+}
+
+#[test]
+fn test_rng() {
+    let mut rng = Rng::new(0);
+    let mut sum = 0.;
+    for _ in 0..1000 {
+        let v = rng.next_f32();
+        assert!((0. ..1.).contains(&v));
+        sum += v;
+    }
+    assert!((sum / 1000. - 0.5).abs() < 0.05);
+    assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+}
+
+#[test]
+fn test_antialiasing_sample() {
+    let mut rng = Rng::new(0);
+    assert_eq!(AntiAliasing::default().sample(0, &mut rng), (0., 0., 1.));
+
+    let aa = AntiAliasing::new(4, SamplePattern::Grid, PixelFilter::Box);
+    let offsets: Vec<_> = (0..4).map(|i| aa.sample(i, &mut rng)).collect();
+    assert_eq!(offsets[0], (-0.25, -0.25, 1.));
+    assert_eq!(offsets[3], (0.25, 0.25, 1.));
+
+    for pattern in [SamplePattern::Jittered, SamplePattern::Random] {
+        let aa = AntiAliasing::new(9, pattern, PixelFilter::Tent);
+        for i in 0..9 {
+            let (dx, dy, w) = aa.sample(i, &mut rng);
+            assert!(dx.abs() <= 1. && dy.abs() <= 1.);
+            assert!((0. ..=1.).contains(&w));
+        }
+    }
+}
+
+#[test]
+fn test_grid_point() {
+    // Cells of the same area tile the square for any number of samples, so the corners of the
+    // cells add up to the area of the square and their centers average to its center
+    for samples in 1..=10 {
+        let mut area = 0.;
+        let (mut u_sum, mut v_sum) = (0., 0.);
+        for i in 0..samples {
+            let (u0, v0) = grid_point(i, samples, 0., 0.);
+            let (u1, v1) = grid_point(i, samples, 1., 1.);
+            assert!(0. <= u0 && 0. <= v0 && u1 <= 1. + 1e-6 && v1 <= 1. + 1e-6);
+            area += (u1 - u0) * (v1 - v0);
+            let (u, v) = grid_point(i, samples, 0.5, 0.5);
+            u_sum += u;
+            v_sum += v;
+        }
+        assert!((area - 1.).abs() < 1e-5, "{}: {}", samples, area);
+        assert!((u_sum / samples as f32 - 0.5).abs() < 1e-5);
+        assert!((v_sum / samples as f32 - 0.5).abs() < 1e-5, "{}", samples);
+    }
+}
+
+#[test]
+fn test_stratified() {
+    let mut rng = Rng::new(7);
+    // Each quadrant gets exactly one of 4 samples
+    let mut quadrants: Vec<_> = (0..4)
+        .map(|i| {
+            let (u, v) = stratified(i, 4, &mut rng);
+            ((u * 2.) as u32, (v * 2.) as u32)
+        })
+        .collect();
+    quadrants.sort();
+    assert_eq!(quadrants, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+}
+
+#[test]
+fn test_sample_disk() {
+    assert_eq!(sample_disk(0.5, 0.5), (0., 0.));
+    let mut rng = Rng::new(3);
+    for _ in 0..100 {
+        let (x, y) = sample_disk(rng.next_f32(), rng.next_f32());
+        assert!(x * x + y * y <= 1. + 1e-6);
+    }
+    let (x, y) = sample_disk(1., 0.5);
+    assert!((x - 1.).abs() < 1e-6 && y.abs() < 1e-6);
+}
+
+#[test]
+fn test_sample_cosine_hemisphere() {
+    let n = Vec3::new(0., 0.6, 0.8);
+    let (t, b) = tangent_frame(&n);
+    assert!(t.dot(&n).abs() < 1e-6 && b.dot(&n).abs() < 1e-6 && t.dot(&b).abs() < 1e-6);
+
+    let mut rng = Rng::new(5);
+    let mut cos_sum = 0.;
+    for _ in 0..1000 {
+        let dir = sample_cosine_hemisphere(&n, rng.next_f32(), rng.next_f32());
+        assert!((dir.len() - 1.).abs() < 1e-4);
+        assert!(0. <= dir.dot(&n));
+        cos_sum += dir.dot(&n);
+    }
+    // The mean cosine of the cosine weighted hemisphere is 2/3
+    assert!((cos_sum / 1000. - 2. / 3.).abs() < 0.03);
+}
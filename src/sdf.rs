@@ -0,0 +1,283 @@
+//! Signed distance functions of shapes that have no analytic ray intersection.
+//!
+//! This module only knows about geometry; the renderable object that binds a shape to a
+//! material is `RenderSdf` in render.rs, which can only be rendered by ray marching.
+use crate::bvh::Aabb;
+use crate::modutil::fmod;
+use crate::vec3::Vec3;
+
+/// A shape centered at the origin of its own space.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SdfShape {
+    /// Box whose edges are rounded by `radius`, within the same `half_extents`
+    RoundBox { half_extents: Vec3, radius: f32 },
+    /// Line segment from `a` to `b` thickened by `r`
+    Capsule { a: Vec3, b: Vec3, r: f32 },
+    /// Ring around the y axis
+    Torus { r: f32, tube_r: f32 },
+    /// The power 8 Mandelbulb fractal by default, fitting in a sphere of about 1.2 times
+    /// `scale`
+    Mandelbulb {
+        #[serde(default = "default_mandelbulb_power")]
+        power: f32,
+        #[serde(default = "default_mandelbulb_iterations")]
+        iterations: u32,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    /// Menger sponge fractal carved out of a cube with `half_size`
+    Menger {
+        half_size: f32,
+        #[serde(default = "default_menger_iterations")]
+        iterations: u32,
+    },
+    /// Infinite copies of `shape` every `period` along each axis, or none along axes whose
+    /// period is 0. The shape should fit in a single cell for the distance to be exact.
+    Repeat { period: Vec3, shape: Box<SdfShape> },
+}
+
+fn default_mandelbulb_power() -> f32 {
+    8.
+}
+
+fn default_mandelbulb_iterations() -> u32 {
+    8
+}
+
+fn default_menger_iterations() -> u32 {
+    4
+}
+
+fn default_scale() -> f32 {
+    1.
+}
+
+fn abs(v: &Vec3) -> Vec3 {
+    Vec3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+/// Exact distance to a box with the given half extents
+fn box_distance(p: &Vec3, half_extents: &Vec3) -> f32 {
+    let q = abs(p) - *half_extents;
+    let outside = Vec3::new(q.x.max(0.), q.y.max(0.), q.z.max(0.)).len();
+    outside + q.x.max(q.y).max(q.z).min(0.)
+}
+
+impl SdfShape {
+    pub fn distance(&self, p: &Vec3) -> f32 {
+        match self {
+            SdfShape::RoundBox {
+                half_extents,
+                radius,
+            } => {
+                let inner = *half_extents - Vec3::new(*radius, *radius, *radius);
+                box_distance(p, &inner) - radius
+            }
+            SdfShape::Capsule { a, b, r } => {
+                let (pa, ba) = (*p - *a, *b - *a);
+                let h = (pa.dot(&ba) / ba.squared_len()).clamp(0., 1.);
+                (pa - ba * h).len() - r
+            }
+            SdfShape::Torus { r, tube_r } => {
+                let rho = (p.x * p.x + p.z * p.z).sqrt() - r;
+                (rho * rho + p.y * p.y).sqrt() - tube_r
+            }
+            SdfShape::Mandelbulb {
+                power,
+                iterations,
+                scale,
+            } => mandelbulb_distance(&(*p * (1. / scale)), *power, *iterations) * scale,
+            SdfShape::Menger {
+                half_size,
+                iterations,
+            } => menger_distance(&(*p * (1. / half_size)), *iterations) * half_size,
+            SdfShape::Repeat { period, shape } => {
+                let wrap = |v: f32, period: f32| {
+                    if period == 0. {
+                        v
+                    } else {
+                        fmod(v + period / 2., period) - period / 2.
+                    }
+                };
+                shape.distance(&Vec3::new(
+                    wrap(p.x, period.x),
+                    wrap(p.y, period.y),
+                    wrap(p.z, period.z),
+                ))
+            }
+        }
+    }
+
+    /// Returns None if the shape is unbounded.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        let cube =
+            |half: f32| Aabb::new(Vec3::new(-half, -half, -half), Vec3::new(half, half, half));
+        match self {
+            SdfShape::RoundBox { half_extents, .. } => {
+                Some(Aabb::new(*half_extents * -1., *half_extents))
+            }
+            SdfShape::Capsule { a, b, r } => {
+                let bounds = Aabb::from_points([a, b]);
+                let r = Vec3::new(*r, *r, *r);
+                Some(Aabb::new(bounds.min - r, bounds.max + r))
+            }
+            SdfShape::Torus { r, tube_r } => {
+                let outer = r + tube_r;
+                Some(Aabb::new(
+                    Vec3::new(-outer, -tube_r, -outer),
+                    Vec3::new(outer, *tube_r, outer),
+                ))
+            }
+            SdfShape::Mandelbulb { scale, .. } => Some(cube(1.2 * scale)),
+            SdfShape::Menger { half_size, .. } => Some(cube(*half_size)),
+            SdfShape::Repeat { .. } => None,
+        }
+    }
+}
+
+/// Distance estimate from the running derivative of the iterated function
+fn mandelbulb_distance(p: &Vec3, power: f32, iterations: u32) -> f32 {
+    let mut z = *p;
+    let mut dr = 1.;
+    let mut r = z.len();
+    for _ in 0..iterations {
+        if 2. < r || r == 0. {
+            break;
+        }
+        // Raise z to the power in spherical coordinates
+        let theta = (z.z / r).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = r.powf(power - 1.) * power * dr + 1.;
+        let zr = r.powf(power);
+        z = Vec3::new(
+            theta.sin() * phi.cos(),
+            phi.sin() * theta.sin(),
+            theta.cos(),
+        ) * zr
+            + *p;
+        r = z.len();
+    }
+    if r == 0. {
+        return 0.;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+/// Cross shaped holes are subtracted at every level from a cube with half size 1.
+fn menger_distance(p: &Vec3, iterations: u32) -> f32 {
+    let mut d = box_distance(p, &Vec3::new(1., 1., 1.));
+    let mut s = 1.;
+    for _ in 0..iterations {
+        let a = Vec3::new(
+            fmod(p.x * s, 2.) - 1.,
+            fmod(p.y * s, 2.) - 1.,
+            fmod(p.z * s, 2.) - 1.,
+        );
+        s *= 3.;
+        let r = Vec3::new(
+            (1. - 3. * a.x.abs()).abs(),
+            (1. - 3. * a.y.abs()).abs(),
+            (1. - 3. * a.z.abs()).abs(),
+        );
+        let da = r.x.max(r.y);
+        let db = r.y.max(r.z);
+        let dc = r.z.max(r.x);
+        d = d.max((da.min(db).min(dc) - 1.) / s);
+    }
+    d
+}
+
+/// Polynomial smooth minimum of two distances blending over a width of `k`. Returns the
+/// distance and the weight of `a` in the blend, from 0 to 1.
+pub fn smooth_min(a: f32, b: f32, k: f32) -> (f32, f32) {
+    if k <= 0. {
+        return if a < b { (a, 1.) } else { (b, 0.) };
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+    (b + (a - b) * h - k * h * (1. - h), h)
+}
+
+/// Step for estimating gradients by finite differences
+const GRADIENT_EPS: f32 = 1e-2;
+
+/// Surface normal estimated from the gradient of the distance function by central differences.
+pub fn gradient_normal(p: &Vec3, distance: impl Fn(&Vec3) -> f32) -> Vec3 {
+    let diff = |dx: f32, dy: f32, dz: f32| {
+        let d = Vec3::new(dx, dy, dz);
+        distance(&(*p + d)) - distance(&(*p - d))
+    };
+    Vec3::new(
+        diff(GRADIENT_EPS, 0., 0.),
+        diff(0., GRADIENT_EPS, 0.),
+        diff(0., 0., GRADIENT_EPS),
+    )
+    .normalized()
+}
+
+#[test]
+fn test_sdf_shapes() {
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+    let round_box = SdfShape::RoundBox {
+        half_extents: Vec3::new(2., 1., 1.),
+        radius: 0.5,
+    };
+    assert!(close(round_box.distance(&Vec3::new(3., 0., 0.)), 1.));
+    assert!(close(round_box.distance(&Vec3::zero()), -1.));
+    // The corner is rounded off, so it is farther than from a sharp box
+    let corner = round_box.distance(&Vec3::new(3., 2., 0.));
+    assert!(close(corner, 2f32.sqrt() * 1.5 - 0.5));
+
+    let capsule = SdfShape::Capsule {
+        a: Vec3::zero(),
+        b: Vec3::new(0., 4., 0.),
+        r: 1.,
+    };
+    assert!(close(capsule.distance(&Vec3::new(3., 2., 0.)), 2.));
+    assert!(close(capsule.distance(&Vec3::new(0., -3., 0.)), 2.));
+
+    let torus = SdfShape::Torus { r: 3., tube_r: 1. };
+    assert!(close(torus.distance(&Vec3::zero()), 2.));
+    assert!(close(torus.distance(&Vec3::new(0., 0., 3.)), -1.));
+
+    let bulb = SdfShape::Mandelbulb {
+        power: 8.,
+        iterations: 8,
+        scale: 1.,
+    };
+    assert!(bulb.distance(&Vec3::zero()) <= 0.);
+    let far = bulb.distance(&Vec3::new(3., 0., 0.));
+    assert!(0. < far && far < 3.);
+
+    let sponge = SdfShape::Menger {
+        half_size: 3.,
+        iterations: 3,
+    };
+    // The center of the cube is hollowed out, while its corners are solid
+    assert!(0. < sponge.distance(&Vec3::zero()));
+    assert!(sponge.distance(&Vec3::new(2.9, 2.9, 2.9)) < 0.);
+    assert!(close(sponge.distance(&Vec3::new(5., 2.9, 2.9)), 2.));
+
+    let repeat = SdfShape::Repeat {
+        period: Vec3::new(10., 0., 0.),
+        shape: Box::new(SdfShape::Torus { r: 3., tube_r: 1. }),
+    };
+    assert!(close(repeat.distance(&Vec3::new(30., 0., 0.)), 2.));
+    assert!(close(repeat.distance(&Vec3::new(33., 0., 0.)), -1.));
+    assert!(close(
+        repeat.distance(&Vec3::new(0., 10., 0.)),
+        10f32.hypot(3.) - 1.
+    ));
+    assert!(repeat.bounding_box().is_none());
+}
+
+#[test]
+fn test_smooth_min() {
+    assert_eq!(smooth_min(1., 5., 2.), (1., 1.));
+    assert_eq!(smooth_min(1., 5., 0.), (1., 1.));
+    let (d, h) = smooth_min(1., 1., 2.);
+    assert_eq!((d, h), (0.5, 0.5));
+
+    let n = gradient_normal(&Vec3::new(3., 4., 0.), |p| p.len() - 1.);
+    assert!((n.x - 0.6).abs() < 1e-3 && (n.y - 0.8).abs() < 1e-3);
+}
@@ -0,0 +1,115 @@
+use crate::framebuffer::Framebuffer;
+use crate::render::RenderColor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ToneMapOperator {
+    /// Values above 1 are clipped when the image is quantized.
+    Clamp,
+    /// x / (1 + x), which compresses highlights smoothly but desaturates them.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+/// Post process from the linear float framebuffer to display values, applied before
+/// writing 8 or 16 bit images. Floating point outputs are left linear.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToneMapping {
+    /// Brightness adjustment in stops; each unit doubles the brightness.
+    pub exposure: f32,
+    pub operator: ToneMapOperator,
+    /// Encode with the sRGB transfer curve instead of writing linear values.
+    pub srgb: bool,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            exposure: 0.,
+            operator: ToneMapOperator::Clamp,
+            srgb: false,
+        }
+    }
+}
+
+impl ToneMapping {
+    #[allow(dead_code)]
+    pub fn new(exposure: f32, operator: ToneMapOperator, srgb: bool) -> Self {
+        Self {
+            exposure,
+            operator,
+            srgb,
+        }
+    }
+
+    fn map_channel(&self, v: f32) -> f32 {
+        let v = v * self.exposure.exp2();
+        let v = match self.operator {
+            ToneMapOperator::Clamp => v,
+            ToneMapOperator::Reinhard => {
+                let v = v.max(0.);
+                v / (1. + v)
+            }
+            ToneMapOperator::Aces => {
+                let v = v.max(0.);
+                (v * (2.51 * v + 0.03) / (v * (2.43 * v + 0.59) + 0.14)).min(1.)
+            }
+        };
+        if self.srgb {
+            srgb_encode(v)
+        } else {
+            v
+        }
+    }
+
+    pub fn apply(&self, c: &RenderColor) -> RenderColor {
+        RenderColor::new(
+            self.map_channel(c.r),
+            self.map_channel(c.g),
+            self.map_channel(c.b),
+        )
+    }
+
+    pub fn apply_framebuffer(
+        &self,
+        framebuffer: &Framebuffer<RenderColor>,
+    ) -> Framebuffer<RenderColor> {
+        framebuffer.map(|c| self.apply(c))
+    }
+}
+
+/// The sRGB transfer function from linear intensity to encoded value.
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.min(1.).powf(1. / 2.4) - 0.055
+    }
+}
+
+#[test]
+fn test_tone_mapping() {
+    let c = RenderColor::new(0.25, 1., 4.);
+    let identity = ToneMapping::default().apply(&c);
+    assert_eq!((identity.r, identity.g, identity.b), (0.25, 1., 4.));
+
+    let exposed = ToneMapping::new(1., ToneMapOperator::Clamp, false).apply(&c);
+    assert_eq!(exposed.r, 0.5);
+
+    let reinhard = ToneMapping::new(0., ToneMapOperator::Reinhard, false).apply(&c);
+    assert_eq!((reinhard.g, reinhard.b), (0.5, 0.8));
+
+    let aces = ToneMapping::new(0., ToneMapOperator::Aces, false);
+    let (lo, hi) = (aces.apply(&c).r, aces.apply(&c).b);
+    assert!(0. < lo && lo < hi && hi <= 1.);
+    assert_eq!(aces.apply(&RenderColor::new(100., 0., 0.)).r, 1.);
+}
+
+#[test]
+fn test_srgb_encode() {
+    assert_eq!(srgb_encode(0.), 0.);
+    assert!((srgb_encode(1.) - 1.).abs() < 1e-6);
+    assert!((srgb_encode(0.214) - 0.5).abs() < 1e-3);
+    assert_eq!(srgb_encode(2.), srgb_encode(1.));
+}
@@ -0,0 +1,92 @@
+use crate::quat::Quat;
+use crate::vec3::Vec3;
+
+/// Affine transformation composed of scale, rotation and translation,
+/// applied in this order.
+#[derive(Clone, Debug, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(
+            Vec3::zero(),
+            Quat::new(0., 0., 0., 1.),
+            Vec3::new(1., 1., 1.),
+        )
+    }
+
+    #[allow(dead_code)]
+    pub fn translation(mut self, v: Vec3) -> Self {
+        self.translation = v;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn rotation(mut self, q: Quat) -> Self {
+        self.rotation = q;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn scale(mut self, v: Vec3) -> Self {
+        self.scale = v;
+        self
+    }
+
+    /// Transform a position from local space into world space.
+    pub fn apply_point(&self, v: &Vec3) -> Vec3 {
+        self.apply_vector(v) + self.translation
+    }
+
+    /// Transform a direction from local space into world space, ignoring translation.
+    pub fn apply_vector(&self, v: &Vec3) -> Vec3 {
+        self.rotation.transform(&Vec3::new(
+            v.x * self.scale.x,
+            v.y * self.scale.y,
+            v.z * self.scale.z,
+        ))
+    }
+
+    /// Transform a position from world space into local space.
+    pub fn inverse_point(&self, v: &Vec3) -> Vec3 {
+        self.inverse_vector(&(*v - self.translation))
+    }
+
+    /// Transform a direction from world space into local space, ignoring translation.
+    pub fn inverse_vector(&self, v: &Vec3) -> Vec3 {
+        let v = self.rotation.conjugated().transform(v);
+        Vec3::new(v.x / self.scale.x, v.y / self.scale.y, v.z / self.scale.z)
+    }
+
+    /// Transform a surface normal from local space into world space.
+    /// Normals are transformed by the inverse transpose, so non-uniform scale divides rather
+    /// than multiplies.
+    pub fn apply_normal(&self, n: &Vec3) -> Vec3 {
+        self.rotation
+            .transform(&Vec3::new(
+                n.x / self.scale.x,
+                n.y / self.scale.y,
+                n.z / self.scale.z,
+            ))
+            .normalized()
+    }
+}
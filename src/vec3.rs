@@ -25,6 +25,14 @@ impl Vec3 {
         self.x * b.x + self.y * b.y + self.z * b.z
     }
 
+    pub fn cross(&self, b: &Self) -> Self {
+        Self::new(
+            self.y * b.z - self.z * b.y,
+            self.z * b.x - self.x * b.z,
+            self.x * b.y - self.y * b.x,
+        )
+    }
+
     pub fn squared_len(&self) -> f32 {
         self.x * self.x + self.y * self.y + self.z * self.z
     }
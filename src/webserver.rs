@@ -1,6 +1,6 @@
 use crate::hyper_adapt::{make_payload_service, payload_service};
 use crate::quat::Quat;
-use crate::render::{render, RenderColor, RenderEnv};
+use crate::render::{render_framebuffer, RenderEnv};
 use ::tokio::io::AsyncReadExt;
 use ::tokio::runtime::Runtime;
 use std::sync::Arc;
@@ -27,26 +27,10 @@ pub struct ServerParams {
     pub ren: RenderEnv,
 }
 
-fn render_web(params: &ServerParams, ren: &RenderEnv) -> Vec<u8> {
-    let (width, height) = (params.width, params.height);
-    let mut data = vec![0u8; 3 * width * height];
-
-    for y in 0..height {
-        for x in 0..width {
-            data[(x + y * width) * 3] = ((x) * 255 / width) as u8;
-            data[(x + y * width) * 3 + 1] = ((y) * 255 / height) as u8;
-            data[(x + y * width) * 3 + 2] = ((x + y) % 32 + 32) as u8;
-        }
-    }
-
-    let mut putpoint = |x: i32, y: i32, fc: &RenderColor| {
-        data[(x as usize + y as usize * width) * 3] = (fc.r * 255.).min(255.) as u8;
-        data[(x as usize + y as usize * width) * 3 + 1] = (fc.g * 255.).min(255.) as u8;
-        data[(x as usize + y as usize * width) * 3 + 2] = (fc.b * 255.).min(255.) as u8;
-    };
-
-    render(&ren, &mut putpoint, params.thread_count);
-    data
+fn render_web(params: &ServerParams, ren: &RenderEnv) -> Result<Vec<u8>, String> {
+    render_framebuffer(ren, params.thread_count)
+        .map(|framebuffer| ren.tone_mapping.apply_framebuffer(&framebuffer).to_rgb8())
+        .map_err(|e| e.to_string())
 }
 
 async fn serve_req(
@@ -272,13 +256,17 @@ async fn serve_req(
         ren.camera.pyr.y = yaw * PI / 180.;
         ren.camera.pyr.x = pitch * PI / 180.;
         ren.camera.rotation = Quat::from_pyr(&ren.camera.pyr);
+        let data = match render_web(&params, &ren) {
+            Ok(data) => data,
+            Err(e) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(format!("fail to render: {}", e)))
+                    .unwrap())
+            }
+        };
         let imbuf = image::DynamicImage::ImageRgb8(
-            image::ImageBuffer::from_raw(
-                params.width as u32,
-                params.height as u32,
-                render_web(&params, &ren),
-            )
-            .unwrap(),
+            image::ImageBuffer::from_raw(params.width as u32, params.height as u32, data).unwrap(),
         );
         let mut buf: Vec<u8> = vec![];
         let mut cur = std::io::Cursor::new(&mut buf);